@@ -0,0 +1,419 @@
+//! Access control rules, read from a JSON file like this one:
+//!
+//! ```json
+//! {
+//!     "groups": { "staff": ["alice", "bob"] },
+//!     "rules": [
+//!         { "path": "/", "users": ["*"], "allow": ["read", "list"] },
+//!         { "path": "/incoming", "users": ["*"], "allow": ["list", "upload"] },
+//!         { "path": "/projects/*", "users": ["@staff"], "allow": ["read", "list", "upload", "delete"] }
+//!     ]
+//! }
+//! ```
+//!
+//! A rule covers the paths matching its glob and everything under them. Users are given by name,
+//! `@group` or `*` for everyone, including clients that didn't log in. The last rule covering a
+//! path and the user decides what they can do there; without one, they can't do anything.
+//!
+//! Rules are matched against where a path really is under the root, as the file system resolves
+//! it, rather than how the request spelled it. On Windows and macOS, where file names are case
+//! insensitive, the globs are too.
+
+use crate::{error::HttpError, filter::Visibility, AppState};
+use actix_web::{http::Method, HttpRequest};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Download files and see them in listings, search results and archives
+    Read,
+    /// See what is in directories
+    List,
+    /// Create files and directories
+    Upload,
+    /// Delete, move and rename files and directories
+    Delete,
+}
+
+#[derive(Deserialize)]
+struct AclFile {
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    rules: Vec<RuleEntry>,
+}
+
+#[derive(Deserialize)]
+struct RuleEntry {
+    path: String,
+    users: Vec<String>,
+    allow: Vec<Permission>,
+}
+
+struct Rule {
+    matcher: GlobMatcher,
+    users: Vec<String>,
+    allow: Vec<Permission>,
+}
+
+pub struct Acl {
+    groups: HashMap<String, Vec<String>>,
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file: AclFile = serde_json::from_slice(&std::fs::read(path)?)?;
+        let mut rules = Vec::with_capacity(file.rules.len());
+        for rule in file.rules {
+            // Paths from the root always start with a slash and never end with one
+            let pattern = format!("/{}", rule.path.trim_matches('/'));
+            let glob = GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .case_insensitive(cfg!(any(windows, target_os = "macos")))
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            rules.push(Rule {
+                matcher: glob.compile_matcher(),
+                users: rule.users,
+                allow: rule.allow,
+            });
+        }
+        Ok(Acl {
+            groups: file.groups,
+            rules,
+        })
+    }
+
+    /// Whether `user` can do what `permission` stands for at `web_path`, a path from the root
+    /// starting with a slash
+    pub fn allows(&self, user: Option<&str>, web_path: &str, permission: Permission) -> bool {
+        let covers = |rule: &&Rule| {
+            Path::new(web_path)
+                .ancestors()
+                .any(|path| rule.matcher.is_match(path))
+        };
+        self.rules
+            .iter()
+            .rev()
+            .filter(|rule| rule.users.iter().any(|subject| self.is(user, subject)))
+            .find(covers)
+            .is_some_and(|rule| rule.allow.contains(&permission))
+    }
+
+    fn is(&self, user: Option<&str>, subject: &str) -> bool {
+        match (subject, user) {
+            ("*", _) => true,
+            (_, None) => false,
+            (subject, Some(user)) => match subject.strip_prefix('@') {
+                Some(group) => self
+                    .groups
+                    .get(group)
+                    .is_some_and(|members| members.iter().any(|member| member == user)),
+                None => subject == user,
+            },
+        }
+    }
+}
+
+/// What one user can do, for checks that happen away from the request, like while walking
+/// directories on another thread
+#[derive(Clone)]
+pub struct Access {
+    acl: Arc<Acl>,
+    serve_from: PathBuf,
+    user: Option<String>,
+    /// The directory whose entries were checked last, and where it really is
+    last_dir: Arc<Mutex<Option<(PathBuf, PathBuf)>>>,
+}
+
+impl Access {
+    /// `path` is an entry found while reading a directory, so its name is already spelled the way
+    /// the file system stores it. Only the directory, once for all its entries, and entries that
+    /// are symlinks need to be resolved.
+    pub fn allows(&self, path: &Path, permission: Permission) -> bool {
+        let resolved = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) if path != self.serve_from && !is_symlink(path) => {
+                self.resolve_dir(dir).join(name)
+            }
+            _ => resolve(&self.serve_from, path),
+        };
+        let web_path = crate::web_path(&self.serve_from, &resolved);
+        self.acl.allows(self.user.as_deref(), &web_path, permission)
+    }
+
+    fn resolve_dir(&self, dir: &Path) -> PathBuf {
+        let mut last_dir = self.last_dir.lock().unwrap_or_else(|e| e.into_inner());
+        match &*last_dir {
+            Some((last, resolved)) if last == dir => resolved.clone(),
+            _ => {
+                let resolved = resolve(&self.serve_from, dir);
+                *last_dir = Some((dir.to_owned(), resolved.clone()));
+                resolved
+            }
+        }
+    }
+}
+
+fn is_symlink(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).map_or(true, |metadata| metadata.is_symlink())
+}
+
+/// Path from the root that rules are matched against for `path`, a path on the server
+fn rule_path(serve_from: &Path, path: &Path) -> String {
+    crate::web_path(serve_from, &resolve(serve_from, path))
+}
+
+/// Where `path` really is under `serve_from`. The part that exists is resolved by the file
+/// system, so that other spellings of a name, like 8.3 short names on Windows, lead to the same
+/// rules. Paths that symlinks lead out of the root by keep the way they were reached.
+fn resolve(serve_from: &Path, path: &Path) -> PathBuf {
+    let resolved = path.ancestors().find_map(|ancestor| {
+        let canonical = std::fs::canonicalize(ancestor).ok()?;
+        let rest = path.strip_prefix(ancestor).ok()?;
+        Some((canonical, rest))
+    });
+    let (base, rest) = match resolved {
+        Some((canonical, rest)) if canonical.starts_with(serve_from) => (canonical, rest),
+        _ => (
+            serve_from.to_owned(),
+            path.strip_prefix(serve_from).unwrap_or(path),
+        ),
+    };
+    rest.iter()
+        .fold(base, |path, name| path.join(file_system_name(name)))
+}
+
+/// Name that the file system stores `name` under. Windows drops trailing dots and spaces.
+fn file_system_name(name: &OsStr) -> OsString {
+    if !cfg!(windows) {
+        return name.to_owned();
+    }
+    trim_windows_name(&name.to_string_lossy()).into()
+}
+
+fn trim_windows_name(name: &str) -> &str {
+    match name.trim_end_matches(['.', ' ']) {
+        "" => name,
+        trimmed => trimmed,
+    }
+}
+
+/// Whether `user` can do what `permission` stands for at `path`, a path on the server
+pub fn allows(
+    data: &AppState<'_>,
+    user: Option<&str>,
+    path: &Path,
+    permission: Permission,
+) -> bool {
+    data.acl
+        .as_ref()
+        .is_none_or(|acl| acl.allows(user, &rule_path(&data.serve_from, path), permission))
+}
+
+pub fn check(
+    data: &AppState<'_>,
+    user: Option<&str>,
+    path: &Path,
+    permission: Permission,
+) -> Result<(), HttpError> {
+    if allows(data, user, path, permission) {
+        Ok(())
+    } else {
+        Err(HttpError::Forbidden)
+    }
+}
+
+/// What `user` gets to see in listings, search results and archives
+pub fn visibility(data: &AppState<'_>, user: Option<&str>) -> Visibility {
+    match &data.acl {
+        Some(acl) => data.visibility.clone().with_access(Access {
+            acl: Arc::clone(acl),
+            serve_from: data.serve_from.clone(),
+            user: user.map(str::to_owned),
+            last_dir: Arc::default(),
+        }),
+        None => data.visibility.clone(),
+    }
+}
+
+/// Checks the WebDAV methods against the rules. Listings, uploads and form actions are checked
+/// where they are handled, since that depends on more than the method.
+pub fn check_request(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    web_path: &str,
+) -> Result<(), HttpError> {
+    let Some(local_path) = data.acl.as_ref().and(data.local_path(web_path)) else {
+        return Ok(());
+    };
+    let permission = match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::POST => return Ok(()),
+        Method::PUT => Permission::Upload,
+        Method::DELETE => Permission::Delete,
+        _ => match req.method().as_str() {
+            "MKCOL" | "PROPPATCH" | "LOCK" | "UNLOCK" => Permission::Upload,
+            "MOVE" => Permission::Delete,
+            "COPY" => Permission::Read,
+            "PROPFIND" if local_path.is_dir() => Permission::List,
+            "PROPFIND" => Permission::Read,
+            _ => return Ok(()),
+        },
+    };
+    let user = crate::auth::user(req);
+    check(data, user.as_deref(), &local_path, permission)?;
+    if matches!(req.method().as_str(), "MOVE" | "COPY") {
+        if let Some(destination) = crate::rename::destination(data, req) {
+            check(data, user.as_deref(), &destination, Permission::Upload)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rule_path, trim_windows_name, Access, Acl, Permission};
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    /// Directory of its own, which is deleted afterwards
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("acl-test-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir(&dir).unwrap();
+            TempDir(dir.canonicalize().unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Everyone can read everything but `/secret`
+    fn acl(dir: &Path) -> Acl {
+        let path = dir.join("acl.json");
+        let rules = r#"{ "rules": [
+            { "path": "/", "users": ["*"], "allow": ["read", "list"] },
+            { "path": "/secret", "users": ["*"], "allow": [] }
+        ] }"#;
+        std::fs::write(&path, rules).unwrap();
+        Acl::load(&path).unwrap()
+    }
+
+    #[test]
+    fn globs_follow_the_case_sensitivity_of_file_names() {
+        let dir = TempDir::new();
+        let acl = acl(&dir.0);
+        assert!(!acl.allows(None, "/secret/file", Permission::Read));
+        assert!(acl.allows(None, "/public/file", Permission::Read));
+        let case_insensitive = cfg!(any(windows, target_os = "macos"));
+        assert_eq!(
+            acl.allows(None, "/SeCrEt/file", Permission::Read),
+            !case_insensitive
+        );
+    }
+
+    #[test]
+    fn rule_paths_are_from_the_root() {
+        let dir = TempDir::new();
+        let root = &dir.0;
+        std::fs::create_dir(root.join("secret")).unwrap();
+        assert_eq!(rule_path(root, root), "/");
+        assert_eq!(rule_path(root, &root.join("secret")), "/secret");
+        // Names that don't exist yet, like those of uploads, are kept
+        assert_eq!(
+            rule_path(root, &root.join("secret").join("new").join("file")),
+            "/secret/new/file"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn other_names_for_a_path_get_its_rules() {
+        let dir = TempDir::new();
+        let root = &dir.0;
+        std::fs::create_dir(root.join("secret")).unwrap();
+        std::os::unix::fs::symlink(root.join("secret"), root.join("alias")).unwrap();
+        let acl = acl(root);
+        let path = rule_path(root, &root.join("alias").join("file"));
+        assert_eq!(path, "/secret/file");
+        assert!(!acl.allows(None, &path, Permission::Read));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listed_entries_get_the_rules_of_where_they_lead() {
+        let dir = TempDir::new();
+        let root = &dir.0;
+        std::fs::create_dir(root.join("secret")).unwrap();
+        std::fs::write(root.join("secret").join("file"), "").unwrap();
+        std::fs::write(root.join("public"), "").unwrap();
+        std::os::unix::fs::symlink(root.join("secret"), root.join("alias")).unwrap();
+        let access = Access {
+            acl: Arc::new(acl(root)),
+            serve_from: root.clone(),
+            user: None,
+            last_dir: Arc::default(),
+        };
+        assert!(access.allows(&root.join("public"), Permission::Read));
+        assert!(!access.allows(&root.join("alias"), Permission::Read));
+        assert!(!access.allows(&root.join("alias").join("file"), Permission::Read));
+        assert!(access.allows(&root.join("public"), Permission::Read));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_out_of_the_root_keep_their_path() {
+        let dir = TempDir::new();
+        let outside = TempDir::new();
+        let root = &dir.0;
+        std::os::unix::fs::symlink(&outside.0, root.join("link")).unwrap();
+        assert_eq!(
+            rule_path(root, &root.join("link").join("file")),
+            "/link/file"
+        );
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_dropped_like_windows_does() {
+        assert_eq!(trim_windows_name("secret"), "secret");
+        assert_eq!(trim_windows_name("secret."), "secret");
+        assert_eq!(trim_windows_name("secret . ."), "secret");
+        assert_eq!(trim_windows_name(".hidden"), ".hidden");
+        assert_eq!(trim_windows_name("..."), "...");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_spellings_get_the_rules_of_the_file() {
+        let dir = TempDir::new();
+        let root = &dir.0;
+        std::fs::create_dir(root.join("secret directory")).unwrap();
+        let acl = acl(root);
+        let mut names = vec!["SECRET DIRECTORY", "secret directory. "];
+        // Volumes can have short names turned off
+        if root.join("SECRET~1").exists() {
+            names.push("SECRET~1");
+        }
+        for name in names {
+            let path = rule_path(root, &root.join(name).join("file"));
+            assert_eq!(path, "/secret directory/file", "{}", name);
+        }
+        let path = rule_path(root, &root.join("secret.").join("file"));
+        assert!(!acl.allows(None, &path, Permission::Read));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::{
+    acl::Permission,
+    archive::ArchiveFormat,
+    error::{error_response, HttpError},
+    webdav::LockTokens,
+    AppState,
+};
+use actix_web::{
+    http::header::LOCATION,
+    web::{self, Bytes, Payload},
+    FromRequest, HttpRequest, HttpResponse,
+};
+use serde::{
+    de::{value::StrDeserializer, IntoDeserializer},
+    Deserialize, Serialize,
+};
+use std::path::{Component, Path, PathBuf};
+
+/// How long share links work if the form doesn't say, in seconds
+const DEFAULT_SHARE_LIFETIME: u64 = 24 * 60 * 60;
+
+/// Fields of a submitted `application/x-www-form-urlencoded` form, in order
+struct Form(Vec<(String, String)>);
+
+impl Form {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Handles the non-upload forms of the listing page, all of which are posted to the directory
+/// they act on. The `action` field picks what to do:
+///
+/// - `download`: archive of the selected entries, in the optional `format`
+/// - `delete`: removes the selected entries, including the contents of directories if
+///   `recursive` is given
+/// - `move`: moves the selected entries into the `destination` directory
+/// - `mkdir`: creates a directory called `folder`
+/// - `rename`: moves the selected entry to `to`, a path relative to the directory or, if it
+///   starts with a slash, to the root
+/// - `share`: JSON with a link to the selected entry that works without logging in, valid for
+///   `expires` seconds and, if given, `max_downloads` downloads and only with `password`
+///
+/// Moves only replace existing targets if `overwrite` is given.
+///
+/// The selected entries are given by `path` fields holding their names in the directory. Forms
+/// sent in a login session need its CSRF token in a `csrf` field.
+pub async fn form_action(
+    data: web::Data<AppState<'_>>,
+    req: HttpRequest,
+    payload: Option<Payload>,
+    web_path: String,
+) -> HttpResponse {
+    let form = match payload {
+        Some(p) => match Bytes::from_request(&req, &mut p.into_inner()).await {
+            Ok(body) => Form(form_urlencoded::parse(&body).into_owned().collect()),
+            Err(_) => return error_response(&data.hbs, HttpError::BadRequest),
+        },
+        None => return error_response(&data.hbs, HttpError::BadRequest),
+    };
+
+    // Downloads are the only action that doesn't change anything, and the only one for visitors
+    // of share links
+    let shared = crate::share::current(&req);
+    let allowed = match form.get("action") {
+        Some("download") => data.mode.can_read(),
+        Some("share") => data.mode.can_read() && shared.is_none(),
+        _ => data.mode.can_change() && shared.is_none(),
+    };
+    if !allowed {
+        return error_response(&data.hbs, HttpError::Forbidden);
+    }
+    if let Err(http_error) = crate::session::check_csrf(&data, &req, form.get("csrf")) {
+        return error_response(&data.hbs, http_error);
+    }
+
+    let user = crate::auth::user(&req);
+    let user = user.as_deref();
+    let tokens = LockTokens::of(&req);
+    let result = match form.get("action") {
+        Some("download") => match &shared {
+            Some(share) => data
+                .shares
+                .count_download(share)
+                .and_then(|()| batch_download(&data, &form, &web_path, user)),
+            None => batch_download(&data, &form, &web_path, user),
+        },
+        Some("delete") => batch_delete(&data, &form, &web_path, user, &tokens).await,
+        Some("move") => batch_move(&data, &form, &web_path, user, &tokens).await,
+        Some("mkdir") => mkdir(&data, &form, &web_path, user, &tokens).await,
+        Some("rename") => rename(&data, &form, &web_path, user, &tokens).await,
+        Some("share") => share(&data, &req, &form, &web_path, user),
+        _ => Err(HttpError::BadRequest),
+    };
+
+    match result {
+        Ok(Some(http_response)) => http_response,
+        // Back to the listing
+        Ok(None) => HttpResponse::SeeOther()
+            .append_header((LOCATION, req.path()))
+            .finish(),
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+/// Validates every selected entry of the form, pairing its path on the server with its name.
+/// `user` needs to have `permission` for all of them.
+fn selected_entries(
+    data: &AppState<'_>,
+    form: &Form,
+    web_path: &str,
+    user: Option<&str>,
+    permission: Permission,
+) -> Result<Vec<(PathBuf, String)>, HttpError> {
+    let mut entries = Vec::new();
+    for name in form.get_all("path") {
+        // Only direct children of the directory can be selected
+        let mut components = Path::new(name).components();
+        let is_child = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        );
+        if !is_child || data.visibility.is_hidden(name) {
+            return Err(HttpError::BadRequest);
+        }
+        let local_path = data
+            .local_path(web_path)
+            .ok_or(HttpError::BadRequest)?
+            .join(name);
+        if std::fs::symlink_metadata(&local_path).is_err() {
+            return Err(HttpError::NotFound);
+        }
+        crate::acl::check(data, user, &local_path, permission)?;
+        entries.push((local_path, name.to_owned()));
+    }
+
+    if entries.is_empty() {
+        return Err(HttpError::BadRequest);
+    }
+    Ok(entries)
+}
+
+fn batch_download(
+    data: &web::Data<AppState<'_>>,
+    form: &Form,
+    web_path: &str,
+    user: Option<&str>,
+) -> Result<Option<HttpResponse>, HttpError> {
+    let entries = selected_entries(data, form, web_path, user, Permission::Read)?;
+    let format = match form.get("format") {
+        Some(format) => {
+            let deserializer: StrDeserializer<'_, serde::de::value::Error> =
+                format.into_deserializer();
+            ArchiveFormat::deserialize(deserializer).map_err(|_| HttpError::BadRequest)?
+        }
+        None => ArchiveFormat::Zip,
+    };
+
+    let archive_name = data.dir_name(web_path);
+    Ok(Some(crate::archive::download_entries(
+        entries,
+        &archive_name,
+        format,
+        crate::acl::visibility(data, user),
+    )))
+}
+
+async fn batch_delete(
+    data: &AppState<'_>,
+    form: &Form,
+    web_path: &str,
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<Option<HttpResponse>, HttpError> {
+    let recursive = form.get("recursive").is_some();
+    for (local_path, _) in selected_entries(data, form, web_path, user, Permission::Delete)? {
+        crate::delete::delete_path(data, &local_path, recursive, tokens).await?;
+    }
+    Ok(None)
+}
+
+async fn batch_move(
+    data: &AppState<'_>,
+    form: &Form,
+    web_path: &str,
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<Option<HttpResponse>, HttpError> {
+    let entries = selected_entries(data, form, web_path, user, Permission::Delete)?;
+    let overwrite = form.get("overwrite").is_some();
+    // Destinations are paths from the root of the served directory
+    let destination = form
+        .get("destination")
+        .map(|destination| destination.trim_start_matches('/'))
+        .and_then(|destination| data.local_path(destination))
+        .ok_or(HttpError::BadRequest)?;
+    if !destination.is_dir() {
+        return Err(HttpError::NotFound);
+    }
+
+    // Check everything first so that a conflict doesn't leave the move half done
+    let mut moves = Vec::with_capacity(entries.len());
+    for (local_path, name) in entries {
+        let target = destination.join(&name);
+        if target.starts_with(&local_path) {
+            return Err(HttpError::Conflict);
+        }
+        if !overwrite && std::fs::symlink_metadata(&target).is_ok() {
+            return Err(HttpError::PreconditionFailed);
+        }
+        crate::acl::check(data, user, &target, Permission::Upload)?;
+        moves.push((local_path, target));
+    }
+    for (local_path, target) in moves {
+        crate::rename::move_path(data, &local_path, &target, overwrite, tokens).await?;
+    }
+    Ok(None)
+}
+
+async fn rename(
+    data: &AppState<'_>,
+    form: &Form,
+    web_path: &str,
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<Option<HttpResponse>, HttpError> {
+    let (from, _) = selected_entries(data, form, web_path, user, Permission::Delete)?
+        .pop()
+        .ok_or(HttpError::BadRequest)?;
+    // Either a path from the root of the served directory or one relative to this directory
+    let to = match form.get("to").ok_or(HttpError::BadRequest)? {
+        to if to.starts_with('/') => to.to_owned(),
+        to => format!("{}/{}", web_path, to),
+    };
+    let to = data
+        .local_path(to.trim_start_matches('/'))
+        .ok_or(HttpError::BadRequest)?;
+    // Nothing hidden, and no name some file system would mangle, can be created by renaming
+    let options = sanitize_filename::Options {
+        windows: true,
+        ..Default::default()
+    };
+    if to.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        data.visibility.is_hidden(&name)
+            || sanitize_filename::sanitize_with_options(&*name, options.clone()) != name
+    }) {
+        return Err(HttpError::BadRequest);
+    }
+    crate::acl::check(data, user, &to, Permission::Upload)?;
+
+    let overwrite = form.get("overwrite").is_some();
+    crate::rename::move_path(data, &from, &to, overwrite, tokens).await?;
+    Ok(None)
+}
+
+async fn mkdir(
+    data: &AppState<'_>,
+    form: &Form,
+    web_path: &str,
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<Option<HttpResponse>, HttpError> {
+    let parent = data.local_path(web_path).ok_or(HttpError::NotFound)?;
+    let name = form.get("folder").ok_or(HttpError::BadRequest)?;
+    crate::mkdir::create_dir(data, &parent, name, user, tokens).await?;
+    Ok(None)
+}
+
+/// Link to a file or directory for someone without an account
+#[derive(Serialize)]
+struct ShareLink {
+    url: String,
+    /// Unix time in seconds at which the link stops working
+    expires: u64,
+}
+
+fn share(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    form: &Form,
+    web_path: &str,
+    user: Option<&str>,
+) -> Result<Option<HttpResponse>, HttpError> {
+    let (local_path, _) = selected_entries(data, form, web_path, user, Permission::Read)?
+        .pop()
+        .ok_or(HttpError::BadRequest)?;
+    let lifetime = match form.get("expires") {
+        Some(expires) => expires.parse().map_err(|_| HttpError::BadRequest)?,
+        None => DEFAULT_SHARE_LIFETIME,
+    };
+    let max_downloads = form
+        .get("max_downloads")
+        .filter(|max_downloads| !max_downloads.is_empty())
+        .map(|max_downloads| max_downloads.parse().map_err(|_| HttpError::BadRequest))
+        .transpose()?;
+    let password = form.get("password").filter(|password| !password.is_empty());
+
+    let (token, expires) = data.shares.mint(
+        data.web_path(&local_path),
+        lifetime,
+        max_downloads,
+        password,
+        user.map(str::to_owned),
+    );
+    let connection_info = req.connection_info();
+    let url = format!(
+        "{}://{}/.share/{}",
+        connection_info.scheme(),
+        connection_info.host(),
+        token
+    );
+    Ok(Some(HttpResponse::Ok().json(ShareLink { url, expires })))
+}
@@ -0,0 +1,302 @@
+use crate::{filter::Visibility, zip::ZipWriter};
+use actix_web::{
+    http::header::{
+        Charset, ContentDisposition, ContentType, DispositionParam, DispositionType, ExtendedValue,
+    },
+    rt::task,
+    web::Bytes,
+    HttpResponse,
+};
+use flate2::{write::GzEncoder, Compression};
+use futures_util::{stream, Stream};
+use serde::Deserialize;
+use std::{
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+/// Size of the chunks sent to the client
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Extensions of already compressed formats, which are stored as-is instead of being deflated
+const STORED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "avi", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jpeg", "jpg",
+    "lz", "lz4", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png", "pptx", "rar",
+    "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+#[derive(Clone, Copy, Deserialize)]
+pub enum ArchiveFormat {
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+}
+
+impl ArchiveFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::TarZst => "application/zstd",
+        }
+    }
+}
+
+/// A file or directory to put in an archive
+struct ArchiveEntry {
+    path: PathBuf,
+    /// Path inside the archive, separated by forward slashes
+    name: String,
+    metadata: std::fs::Metadata,
+}
+
+/// Streams an archive of `dir_path` and everything under it, put inside a directory called
+/// `name` in the archive.
+pub fn download(
+    dir_path: PathBuf,
+    name: String,
+    format: ArchiveFormat,
+    visibility: Visibility,
+) -> HttpResponse {
+    let archive_name = name.clone();
+    download_entries(vec![(dir_path, name)], &archive_name, format, visibility)
+}
+
+/// Streams an archive of several files and directories, each paired with its name in the
+/// archive. The download is called `archive_name` plus the format's extension. Only what is
+/// visible under `visibility` ends up in the archive.
+pub fn download_entries(
+    roots: Vec<(PathBuf, String)>,
+    archive_name: &str,
+    format: ArchiveFormat,
+    visibility: Visibility,
+) -> HttpResponse {
+    let file_name = format!("{}.{}", archive_name, format.extension());
+    let body = stream_archive(format, roots, visibility);
+
+    HttpResponse::Ok()
+        .insert_header(ContentType(format.content_type().parse().unwrap()))
+        .insert_header(attachment(&file_name))
+        .streaming(body)
+}
+
+/// `Content-Disposition` header for downloading a file called `file_name`
+fn attachment(file_name: &str) -> ContentDisposition {
+    let mut parameters = vec![DispositionParam::Filename(
+        file_name
+            .chars()
+            .map(|c| if c.is_ascii() { c } else { '_' })
+            .collect(),
+    )];
+    if !file_name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_owned()),
+            language_tag: None,
+            value: file_name.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Builds the archive on a blocking thread, handing it over in chunks as it is written. Nothing
+/// is buffered beyond a few chunks.
+fn stream_archive(
+    format: ArchiveFormat,
+    roots: Vec<(PathBuf, String)>,
+    visibility: Visibility,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(4);
+    task::spawn_blocking(move || {
+        let writer = ChannelWriter {
+            tx: tx.clone(),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        let entries = archive_entries(&roots, &visibility);
+        let result = match format {
+            ArchiveFormat::Zip => write_zip(writer, entries),
+            ArchiveFormat::Tar => write_tar(writer, entries).and_then(|mut writer| writer.flush()),
+            ArchiveFormat::TarGz => {
+                let encoder = GzEncoder::new(writer, Compression::default());
+                write_tar(encoder, entries)
+                    .and_then(GzEncoder::finish)
+                    .and_then(|mut writer| writer.flush())
+            }
+            ArchiveFormat::TarZst => zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .and_then(|encoder| write_tar(encoder, entries))
+                .and_then(zstd::Encoder::finish)
+                .and_then(|mut writer| writer.flush()),
+        };
+        if let Err(e) = result {
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                return;
+            }
+            eprintln!("Archive error: {}", e);
+            // Make the response fail instead of silently ending with a truncated archive
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    })
+}
+
+fn write_zip(writer: ChannelWriter, entries: impl Iterator<Item = ArchiveEntry>) -> io::Result<()> {
+    let mut zip = ZipWriter::new(writer);
+    for entry in entries {
+        let modified = entry.metadata.modified()?;
+        let mode = permissions_mode(&entry.metadata);
+        if entry.metadata.is_dir() {
+            zip.add_directory(format!("{}/", entry.name), modified, mode)?;
+        } else {
+            // Files that disappeared since being listed are left out
+            let mut file = match std::fs::File::open(&entry.path) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            let compress = !is_compressed(&entry.path);
+            zip.add_file(
+                entry.name,
+                modified,
+                mode,
+                entry.metadata.len(),
+                compress,
+                &mut file,
+            )?;
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Writes a tarball with the permissions and modification times of the entries, returning the
+/// inner writer.
+fn write_tar<W: Write>(writer: W, entries: impl Iterator<Item = ArchiveEntry>) -> io::Result<W> {
+    let mut tar = tar::Builder::new(writer);
+    tar.follow_symlinks(false);
+    for entry in entries {
+        if entry.metadata.is_dir() {
+            tar.append_dir(&entry.name, &entry.path)?;
+        } else {
+            // Files that disappeared since being listed are left out
+            let mut file = match std::fs::File::open(&entry.path) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            tar.append_file(&entry.name, &mut file)?;
+        }
+    }
+    tar.into_inner()
+}
+
+/// Every visible file and directory under `roots`, depth-first in file name order. Each root is
+/// paired with its path inside the archive. Symlinks are skipped so that nothing outside of the
+/// served directory ends up in the archive.
+fn archive_entries<'a>(
+    roots: &'a [(PathBuf, String)],
+    visibility: &'a Visibility,
+) -> impl Iterator<Item = ArchiveEntry> + 'a {
+    roots.iter().flat_map(move |(root, root_name)| {
+        WalkDir::new(root)
+            .follow_links(false)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(move |entry| entry.depth() == 0 || !visibility.hides(entry.path()))
+            .filter_map(Result::ok)
+            .filter_map(move |entry| {
+                let metadata = entry.metadata().ok()?;
+                if metadata.file_type().is_symlink() {
+                    return None;
+                }
+                let mut name = root_name.clone();
+                for component in entry.path().strip_prefix(root).ok()?.components() {
+                    name.push('/');
+                    name.push_str(&component.as_os_str().to_string_lossy());
+                }
+                Some(ArchiveEntry {
+                    path: entry.into_path(),
+                    name,
+                    metadata,
+                })
+            })
+    })
+}
+
+fn is_compressed(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            STORED_EXTENSIONS
+                .iter()
+                .any(|stored| stored.eq_ignore_ascii_case(extension))
+        })
+}
+
+#[cfg(unix)]
+fn permissions_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions_mode(metadata: &std::fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+/// Sends whatever is written to it over a channel, in chunks of `CHUNK_SIZE`
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send_buf(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        // The receiver is gone if the client disconnected
+        self.tx
+            .blocking_send(Ok(Bytes::from(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buf()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buf()
+    }
+}
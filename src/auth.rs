@@ -0,0 +1,290 @@
+//! HTTP Basic authentication against an htpasswd file. Only bcrypt hashes, as made by
+//! `htpasswd -B`, and Argon2 hashes in PHC format are accepted, since the other htpasswd formats
+//! are too weak to be worth supporting. Browsers can log in with a form instead, see `session`.
+
+use crate::{
+    error::{error_response, HttpError},
+    signing::Signer,
+    AppState,
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderValue},
+        Method,
+    },
+    web, Error, HttpMessage, HttpRequest, HttpResponse,
+};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::{collections::HashMap, io, path::Path, rc::Rc, sync::Mutex};
+
+/// Users that can log in, loaded from an htpasswd file
+pub struct Htpasswd {
+    hashes: HashMap<String, String>,
+    /// `WWW-Authenticate` header asking for credentials
+    challenge: HeaderValue,
+    /// Signatures of the passwords that were already checked against the slow hashes, so that
+    /// they aren't checked again for every request. Their key is made at startup, so unlike plain
+    /// digests they can't be checked against guesses without it.
+    signer: Signer,
+    verified: Mutex<HashMap<String, String>>,
+}
+
+/// Name of the user that sent a request, found in the request extensions
+#[derive(Clone)]
+pub struct User(pub String);
+
+impl Htpasswd {
+    /// Reads `user:hash` lines from `path`. Empty lines and lines starting with `#` are skipped.
+    pub fn load(path: &Path, realm: &str) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut hashes = HashMap::new();
+        for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("Line {} is not of the form user:hash", i + 1)))?;
+            let supported = if hash.starts_with("$argon2") {
+                PasswordHash::new(hash).is_ok()
+            } else {
+                ["$2a$", "$2b$", "$2y$"]
+                    .iter()
+                    .any(|prefix| hash.starts_with(prefix))
+            };
+            if !supported {
+                return Err(invalid(format!(
+                    "The password of {} is not a bcrypt or Argon2 hash",
+                    user
+                )));
+            }
+            hashes.insert(user.to_owned(), hash.to_owned());
+        }
+
+        let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let challenge =
+            HeaderValue::from_str(&format!(r#"Basic realm="{}", charset="UTF-8""#, realm))
+                .map_err(|_| invalid("The realm can't be used in a header".to_owned()))?;
+        Ok(Htpasswd {
+            hashes,
+            challenge,
+            signer: Signer::new(),
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Checks a password. This can take a while, so it shouldn't be run on the async threads.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let verified = self
+            .verified
+            .lock()
+            .unwrap()
+            .get(user)
+            .is_some_and(|signature| self.signer.verify("password", password, signature));
+        if verified {
+            return true;
+        }
+        let Some(hash) = self.hashes.get(user) else {
+            // Takes about as long as a wrong password, so that which users exist doesn't show
+            if let Some(hash) = self.hashes.values().next() {
+                check_hash(hash, password);
+            }
+            return false;
+        };
+        let valid = check_hash(hash, password);
+        if valid {
+            let signature = self.signer.sign("password", password);
+            self.verified
+                .lock()
+                .unwrap()
+                .insert(user.to_owned(), signature);
+        }
+        valid
+    }
+}
+
+fn check_hash(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// Name of the user that sent `req`, if users have to log in
+pub fn user(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<User>().map(|user| user.0.clone())
+}
+
+/// User name and password from an `Authorization: Basic` header
+fn credentials(req: &ServiceRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+/// Whether `req` is a browser loading a page
+fn is_page_request(req: &ServiceRequest) -> bool {
+    req.method() == Method::GET
+        && req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// Middleware that lets requests through only with the credentials of a user in the htpasswd
+/// file or the cookie of a session they started, if there is such a file, or for share links.
+/// The name of the user is added to the request as a `User`, and every request is logged with it.
+pub struct BasicAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for BasicAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = BasicAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BasicAuthMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct BasicAuthMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for BasicAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+        let method = req.method().clone();
+        let path = req.path().to_owned();
+        let response = Self::authenticate(service, req);
+        Box::pin(async move {
+            let response = response.await;
+            let (user, status) = match &response {
+                Ok(response) => (user(response.request()), response.status()),
+                Err(e) => (None, e.as_response_error().status_code()),
+            };
+            eprintln!(
+                "{} {} {} {} {}",
+                peer.as_deref().unwrap_or("-"),
+                user.as_deref().unwrap_or("-"),
+                method,
+                path,
+                status.as_u16()
+            );
+            response
+        })
+    }
+}
+
+impl<S, B> BasicAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    /// Lets `req` through to `service` if it may be, or answers it with what it needs to log in
+    fn authenticate(
+        service: Rc<S>,
+        req: ServiceRequest,
+    ) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>> {
+        Box::pin(async move {
+            let data = req
+                .app_data::<web::Data<AppState<'static>>>()
+                .cloned()
+                .expect("The app state is always registered");
+            let credentials = data.auth.as_ref().and_then(|_| credentials(&req));
+            if let Some((user, password)) = credentials {
+                let verifier = data.clone();
+                let checked_user = user.clone();
+                let valid = web::block(move || {
+                    verifier
+                        .auth
+                        .as_ref()
+                        .is_some_and(|auth| auth.verify(&checked_user, &password))
+                })
+                .await
+                .unwrap_or(false);
+                if valid {
+                    req.extensions_mut().insert(User(user));
+                    return service.call(req).await.map(|res| res.map_into_left_body());
+                }
+                let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+                eprintln!(
+                    "Failed login as {} from {}",
+                    user,
+                    peer.as_deref().unwrap_or("unknown address")
+                );
+            }
+
+            if data.auth.is_some() && crate::session::attach(&data, &req) {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+
+            // The login form needs the static files. Visitors of share links don't log in; the
+            // links themselves are checked by `share`.
+            let is_public = req.path() == "/.login"
+                || req.path().starts_with("/static/")
+                || req.path().starts_with("/.share/")
+                || crate::share::attach(&data, &req);
+            if is_public || data.auth.is_none() {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+
+            // Browsers get the login form rather than their own prompt
+            if is_page_request(&req) {
+                let next = req
+                    .uri()
+                    .path_and_query()
+                    .map_or("/", |path_and_query| path_and_query.as_str());
+                let query = form_urlencoded::Serializer::new(String::new())
+                    .append_pair("next", next)
+                    .finish();
+                let response = HttpResponse::SeeOther()
+                    .append_header((header::LOCATION, format!("/.login?{}", query)))
+                    .finish();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut response = error_response(&data.hbs, HttpError::Unauthorized);
+            if let Some(auth) = &data.auth {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, auth.challenge.clone());
+            }
+            Ok(req.into_response(response).map_into_right_body())
+        })
+    }
+}
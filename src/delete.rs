@@ -0,0 +1,84 @@
+use crate::{
+    error::{error_response, HttpError},
+    webdav::LockTokens,
+    AppState,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::{io, path::Path};
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    recursive: bool,
+}
+
+/// Handles `DELETE` requests. Directories are only removed together with their contents when
+/// `?recursive=true` or `Depth: infinity` is given.
+pub async fn delete(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    web_path: String,
+) -> HttpResponse {
+    // WebDAV clients ask for directories to be deleted with everything in them by `Depth`
+    let depth_infinity = req
+        .headers()
+        .get("Depth")
+        .is_some_and(|depth| depth.as_bytes().eq_ignore_ascii_case(b"infinity"));
+    let recursive = match web::Query::<DeleteQuery>::from_query(req.query_string()) {
+        Ok(query) => query.recursive || depth_infinity,
+        Err(_) => return error_response(&data.hbs, HttpError::BadRequest),
+    };
+
+    let result = match data.local_path(&web_path) {
+        Some(local_path) => delete_path(&data, &local_path, recursive, &LockTokens::of(req)).await,
+        None => Err(HttpError::NotFound),
+    };
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+/// Removes a file or directory, checking that deletion is allowed and that `tokens` has the
+/// tokens of the locks on it. Non-empty directories are a conflict unless `recursive` is set.
+pub async fn delete_path(
+    data: &AppState<'_>,
+    local_path: &Path,
+    recursive: bool,
+    tokens: &LockTokens,
+) -> Result<(), HttpError> {
+    if !data.allow_delete || local_path == data.serve_from {
+        return Err(HttpError::Forbidden);
+    }
+    data.dav.check_writable(local_path, true, true, tokens)?;
+
+    let metadata = tokio::fs::symlink_metadata(local_path)
+        .await
+        .map_err(|_| HttpError::NotFound)?;
+    let result = if !metadata.is_dir() {
+        tokio::fs::remove_file(local_path).await
+    } else if recursive {
+        tokio::fs::remove_dir_all(local_path).await
+    } else {
+        let mut dir_reader = tokio::fs::read_dir(local_path)
+            .await
+            .map_err(|_| HttpError::NotFound)?;
+        if let Ok(Some(_)) = dir_reader.next_entry().await {
+            return Err(HttpError::Conflict);
+        }
+        tokio::fs::remove_dir(local_path).await
+    };
+
+    if result.is_ok() {
+        data.dav.removed(local_path);
+    }
+    result.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => HttpError::NotFound,
+        io::ErrorKind::PermissionDenied => HttpError::Forbidden,
+        _ => {
+            eprintln!("Delete error: {}", e);
+            HttpError::InternalServerError
+        }
+    })
+}
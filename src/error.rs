@@ -1,4 +1,4 @@
-use actix_web::HttpResponse;
+use actix_web::{http::StatusCode, HttpResponse};
 use handlebars::Handlebars;
 use serde::Serialize;
 
@@ -8,13 +8,88 @@ struct ErrorTemplateContext {
     text: &'static str,
 }
 
+#[derive(Clone, Copy)]
 pub enum HttpError {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
     NotFound,
+    MethodNotAllowed,
+    Conflict,
+    Gone,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    UnprocessableEntity,
+    Locked,
     InternalServerError,
 }
 
+/// Short description of an error for JSON responses
+#[derive(Serialize)]
+struct ErrorSummary {
+    status: u16,
+    message: &'static str,
+}
+
+impl HttpError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HttpError::BadRequest => StatusCode::BAD_REQUEST,
+            HttpError::Unauthorized => StatusCode::UNAUTHORIZED,
+            HttpError::Forbidden => StatusCode::FORBIDDEN,
+            HttpError::NotFound => StatusCode::NOT_FOUND,
+            HttpError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            HttpError::Conflict => StatusCode::CONFLICT,
+            HttpError::Gone => StatusCode::GONE,
+            HttpError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            HttpError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            HttpError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            HttpError::UnprocessableEntity => StatusCode::UNPROCESSABLE_ENTITY,
+            HttpError::Locked => StatusCode::LOCKED,
+            HttpError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn summary(&self) -> ErrorSummary {
+        let status = self.status();
+        ErrorSummary {
+            status: status.as_u16(),
+            message: status.canonical_reason().unwrap_or_default(),
+        }
+    }
+}
+
+/// Errors show up in JSON responses as their summaries
+impl Serialize for HttpError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.summary().serialize(serializer)
+    }
+}
+
 pub fn error_response(hbs: &Handlebars<'_>, http_error: HttpError) -> HttpResponse {
     let (mut builder, context) = match http_error {
+        HttpError::BadRequest => (
+            HttpResponse::BadRequest(),
+            ErrorTemplateContext {
+                title: "400: Bad Request",
+                text: "The request could not be understood by the server.",
+            },
+        ),
+        HttpError::Unauthorized => (
+            HttpResponse::Unauthorized(),
+            ErrorTemplateContext {
+                title: "401: Unauthorized",
+                text: "You need to log in to see this.",
+            },
+        ),
+        HttpError::Forbidden => (
+            HttpResponse::Forbidden(),
+            ErrorTemplateContext {
+                title: "403: Forbidden",
+                text: "You are not allowed to do this.",
+            },
+        ),
         HttpError::NotFound => (
             HttpResponse::NotFound(),
             ErrorTemplateContext {
@@ -22,6 +97,63 @@ pub fn error_response(hbs: &Handlebars<'_>, http_error: HttpError) -> HttpRespon
                 text: "The requested resource could not be found.",
             },
         ),
+        HttpError::MethodNotAllowed => (
+            HttpResponse::MethodNotAllowed(),
+            ErrorTemplateContext {
+                title: "405: Method Not Allowed",
+                text: "The requested resource does not support this method.",
+            },
+        ),
+        HttpError::Conflict => (
+            HttpResponse::Conflict(),
+            ErrorTemplateContext {
+                title: "409: Conflict",
+                text: "The request conflicts with the current state of the server.",
+            },
+        ),
+        HttpError::Gone => (
+            HttpResponse::Gone(),
+            ErrorTemplateContext {
+                title: "410: Gone",
+                text: "This link has expired or can't be used any more.",
+            },
+        ),
+        HttpError::PreconditionFailed => (
+            HttpResponse::PreconditionFailed(),
+            ErrorTemplateContext {
+                title: "412: Precondition Failed",
+                text:
+                    "A condition of the request was not met, such as the target not existing yet.",
+            },
+        ),
+        HttpError::PayloadTooLarge => (
+            HttpResponse::PayloadTooLarge(),
+            ErrorTemplateContext {
+                title: "413: Payload Too Large",
+                text: "The upload is larger than the server allows.",
+            },
+        ),
+        HttpError::UnsupportedMediaType => (
+            HttpResponse::UnsupportedMediaType(),
+            ErrorTemplateContext {
+                title: "415: Unsupported Media Type",
+                text: "The request body is of a type the server does not understand or accept.",
+            },
+        ),
+        HttpError::UnprocessableEntity => (
+            HttpResponse::UnprocessableEntity(),
+            ErrorTemplateContext {
+                title: "422: Unprocessable Entity",
+                text: "The upload does not match the digest that was sent with it.",
+            },
+        ),
+        HttpError::Locked => (
+            HttpResponse::Locked(),
+            ErrorTemplateContext {
+                title: "423: Locked",
+                text: "The resource is locked and the request did not include its lock token.",
+            },
+        ),
         HttpError::InternalServerError => (
             HttpResponse::InternalServerError(),
             ErrorTemplateContext {
@@ -36,3 +168,8 @@ pub fn error_response(hbs: &Handlebars<'_>, http_error: HttpError) -> HttpRespon
             .expect("Handlebars failed at rendering"),
     )
 }
+
+/// Error response for clients that asked for JSON
+pub fn error_json_response(http_error: HttpError) -> HttpResponse {
+    HttpResponse::build(http_error.status()).json(serde_json::json!({ "error": http_error }))
+}
@@ -0,0 +1,196 @@
+//! Unpacking of uploaded archives. Entries are written to numbered files in a staging directory
+//! instead of under their own names, so nothing in an archive decides where data goes on disk.
+//! `upload` then places them with the same checks as any other uploaded file.
+
+use crate::error::HttpError;
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+use tar::EntryType;
+
+/// Most entries an archive can have
+const MAX_ENTRIES: usize = 10_000;
+/// How many times larger than the archive its contents can be, which stops zip bombs...
+const MAX_RATIO: u64 = 100;
+/// ...unless they are smaller than this anyway
+const MIN_BUDGET: u64 = 16 << 20;
+
+#[derive(Clone, Copy)]
+pub enum ArchiveKind {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Entry {
+    /// Path of the entry inside the archive, as the archive gives it
+    pub name: String,
+    /// `None` for entries that are not regular files, like symlinks, which are never unpacked
+    pub staged: Option<Staged>,
+}
+
+pub struct Staged {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the contents
+    pub sha256: String,
+}
+
+/// Unpacks the files in `archive` into the existing directory `staging`. Directory entries are
+/// left out since the paths of the files imply them. The contents can't add up to more than
+/// `max_size`, besides the limit relative to the size of the archive.
+pub async fn unpack(
+    kind: ArchiveKind,
+    archive: PathBuf,
+    staging: PathBuf,
+    max_size: Option<u64>,
+) -> Result<Vec<Entry>, HttpError> {
+    tokio::task::spawn_blocking(move || {
+        let file = File::open(&archive).map_err(io_error)?;
+        let archive_size = file.metadata().map_err(io_error)?.len();
+        let budget = archive_size.saturating_mul(MAX_RATIO).max(MIN_BUDGET);
+        let mut unpacker = Unpacker {
+            staging,
+            budget: max_size.map_or(budget, |max_size| budget.min(max_size)),
+            entries: Vec::new(),
+        };
+        match kind {
+            ArchiveKind::Zip => unpacker.unpack_zip(file)?,
+            ArchiveKind::TarGz => unpacker.unpack_tar(GzDecoder::new(file))?,
+        }
+        Ok(unpacker.entries)
+    })
+    .await
+    .map_err(|_| HttpError::InternalServerError)?
+}
+
+struct Unpacker {
+    staging: PathBuf,
+    /// How much more can be unpacked
+    budget: u64,
+    entries: Vec<Entry>,
+}
+
+impl Unpacker {
+    fn unpack_zip(&mut self, file: File) -> Result<(), HttpError> {
+        let mut archive = ::zip::ZipArchive::new(file).map_err(zip_error)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(zip_error)?;
+            if entry.is_dir() {
+                continue;
+            }
+            let name = entry.name().to_owned();
+            // Symlinks are stored as files holding their targets
+            let is_symlink = entry
+                .unix_mode()
+                .is_some_and(|mode| mode & 0o170000 == 0o120000);
+            if is_symlink {
+                self.skip(name)?;
+            } else {
+                self.stage(name, &mut entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn unpack_tar(&mut self, reader: impl Read) -> Result<(), HttpError> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().map_err(|_| HttpError::BadRequest)? {
+            let mut entry = entry.map_err(|_| HttpError::BadRequest)?;
+            let name = entry
+                .path()
+                .map_err(|_| HttpError::BadRequest)?
+                .to_string_lossy()
+                .into_owned();
+            match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => self.stage(name, &mut entry)?,
+                // Extension headers only describe other entries
+                EntryType::Directory
+                | EntryType::XGlobalHeader
+                | EntryType::XHeader
+                | EntryType::GNULongName
+                | EntryType::GNULongLink => (),
+                _ => self.skip(name)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn stage(&mut self, name: String, reader: &mut impl Read) -> Result<(), HttpError> {
+        if self.entries.len() == MAX_ENTRIES {
+            return Err(HttpError::PayloadTooLarge);
+        }
+        let path = self.staging.join(self.entries.len().to_string());
+        let mut file = File::create(&path).map_err(io_error)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        let mut buf = vec![0; 64 << 10];
+        loop {
+            // Archives that can't be read are the client's problem
+            let n = reader.read(&mut buf).map_err(|_| HttpError::BadRequest)?;
+            if n == 0 {
+                break;
+            }
+            // Sizes in archives can lie, so only what was actually unpacked counts
+            size += n as u64;
+            if size > self.budget {
+                return Err(HttpError::PayloadTooLarge);
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n]).map_err(io_error)?;
+        }
+        self.budget -= size;
+        self.entries.push(Entry {
+            name,
+            staged: Some(Staged {
+                path,
+                size,
+                sha256: format!("{:x}", hasher.finalize()),
+            }),
+        });
+        Ok(())
+    }
+
+    fn skip(&mut self, name: String) -> Result<(), HttpError> {
+        if self.entries.len() == MAX_ENTRIES {
+            return Err(HttpError::PayloadTooLarge);
+        }
+        self.entries.push(Entry { name, staged: None });
+        Ok(())
+    }
+}
+
+fn zip_error(e: ::zip::result::ZipError) -> HttpError {
+    match e {
+        ::zip::result::ZipError::Io(e) => io_error(e),
+        // Invalid, encrypted or using unsupported compression
+        _ => HttpError::BadRequest,
+    }
+}
+
+fn io_error(e: io::Error) -> HttpError {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => HttpError::Forbidden,
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => HttpError::BadRequest,
+        _ => {
+            eprintln!("Extract error: {}", e);
+            HttpError::InternalServerError
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::acl::{Access, Permission};
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::path::Path;
+
+/// How a pattern given by the client is interpreted.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Glob if the pattern contains glob metacharacters, substring otherwise
+    #[default]
+    Auto,
+    Substring,
+    Glob,
+    Regex,
+}
+
+/// Case-insensitive matcher for file names.
+pub enum NameFilter {
+    Glob(GlobMatcher),
+    Substring(String),
+    Regex(Regex),
+}
+
+impl NameFilter {
+    pub fn new(pattern: &str, mode: MatchMode) -> Result<Self, Box<dyn std::error::Error>> {
+        let mode = match mode {
+            MatchMode::Auto if pattern.contains(['*', '?', '[', '{']) => MatchMode::Glob,
+            MatchMode::Auto => MatchMode::Substring,
+            mode => mode,
+        };
+
+        let name_filter = match mode {
+            MatchMode::Glob => {
+                let glob = GlobBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .literal_separator(true)
+                    .build()?;
+                NameFilter::Glob(glob.compile_matcher())
+            }
+            MatchMode::Regex => {
+                let regex = RegexBuilder::new(pattern).case_insensitive(true).build()?;
+                NameFilter::Regex(regex)
+            }
+            _ => NameFilter::Substring(pattern.to_lowercase()),
+        };
+        Ok(name_filter)
+    }
+
+    pub fn is_match(&self, file_name: &str) -> bool {
+        match self {
+            NameFilter::Glob(matcher) => matcher.is_match(file_name),
+            NameFilter::Substring(substring) => file_name.to_lowercase().contains(substring),
+            NameFilter::Regex(regex) => regex.is_match(file_name),
+        }
+    }
+}
+
+/// Rules for leaving entries out of listings, search results and archives.
+#[derive(Clone)]
+pub struct Visibility {
+    show_hidden: bool,
+    exclude: GlobSet,
+    /// Who is looking, if what they see depends on the access rules
+    access: Option<Access>,
+}
+
+impl Visibility {
+    pub fn new(show_hidden: bool, exclude: &[String]) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in exclude {
+            builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+        }
+        Ok(Visibility {
+            show_hidden,
+            exclude: builder.build()?,
+            access: None,
+        })
+    }
+
+    /// Also hides what `access` doesn't allow to be read
+    pub fn with_access(self, access: Access) -> Self {
+        Visibility {
+            access: Some(access),
+            ..self
+        }
+    }
+
+    /// Hidden files are the ones whose names start with a dot or match an exclude pattern
+    pub fn is_hidden(&self, file_name: &str) -> bool {
+        (!self.show_hidden && file_name.starts_with('.')) || self.exclude.is_match(file_name)
+    }
+
+    /// Whether the entry at `path` is hidden, either by its name or by the access rules
+    pub fn hides(&self, path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| self.is_hidden(&name.to_string_lossy()))
+            || self
+                .access
+                .as_ref()
+                .is_some_and(|access| !access.allows(path, Permission::Read))
+    }
+}
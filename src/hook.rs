@@ -0,0 +1,125 @@
+use std::{
+    path::PathBuf,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+
+/// How many runs can wait for one of the others to finish
+const MAX_WAITING: usize = 100;
+
+/// Command run in the background for every stored upload. It gets the path of the file as its
+/// argument and the rest of what is known about the upload in `UPLOAD_*` environment variables.
+#[derive(Clone)]
+pub struct UploadHook {
+    command: PathBuf,
+    timeout: Duration,
+    permits: Arc<Semaphore>,
+    waiting: Arc<AtomicUsize>,
+}
+
+/// A stored upload, as described to the hook
+pub struct UploadEvent {
+    /// Where the file is on the server
+    pub path: PathBuf,
+    /// Path of the file from the root, as in URLs
+    pub web_path: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+    /// How the file was uploaded: `form`, `put` or `tus`
+    pub source: &'static str,
+    /// Who uploaded the file, if users have to log in
+    pub user: Option<String>,
+}
+
+impl UploadHook {
+    /// Runs `command` for at most `timeout`, with no more than `jobs` at a time
+    pub fn new(command: PathBuf, timeout: Duration, jobs: usize) -> Self {
+        UploadHook {
+            command,
+            timeout,
+            permits: Arc::new(Semaphore::new(jobs.max(1))),
+            waiting: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Queues the command for `event` without waiting for it. When too many runs are waiting
+    /// already, it is left out.
+    pub fn run(&self, event: UploadEvent) {
+        let queued = self
+            .waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < MAX_WAITING).then_some(waiting + 1)
+            });
+        if queued.is_err() {
+            eprintln!(
+                "Upload hook for {} not run, {} runs are waiting already",
+                event.path.display(),
+                MAX_WAITING
+            );
+            return;
+        }
+        let hook = self.clone();
+        actix_web::rt::spawn(async move {
+            // Closed semaphores don't happen since the hook holds on to it
+            let permit = hook.permits.acquire().await;
+            hook.waiting.fetch_sub(1, Ordering::AcqRel);
+            let Ok(_permit) = permit else {
+                return;
+            };
+            hook.execute(&event).await;
+        });
+    }
+
+    async fn execute(&self, event: &UploadEvent) {
+        let mut command = tokio::process::Command::new(&self.command);
+        command
+            .arg(&event.path)
+            .env("UPLOAD_PATH", &event.path)
+            .env("UPLOAD_WEB_PATH", &event.web_path)
+            .env("UPLOAD_SIZE", event.size.to_string())
+            .env("UPLOAD_SOURCE", event.source)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        if let Some(sha256) = &event.sha256 {
+            command.env("UPLOAD_SHA256", sha256);
+        }
+        if let Some(user) = &event.user {
+            command.env("UPLOAD_USER", user);
+        }
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!(
+                    "Upload hook for {} failed to start: {}",
+                    event.path.display(),
+                    e
+                );
+                return;
+            }
+        };
+        match tokio::time::timeout(self.timeout, child.wait()).await {
+            Ok(Ok(status)) => {
+                eprintln!(
+                    "Upload hook for {} exited with {}",
+                    event.path.display(),
+                    status
+                )
+            }
+            Ok(Err(e)) => eprintln!("Upload hook for {} failed: {}", event.path.display(), e),
+            Err(_) => {
+                let _ = child.kill().await;
+                eprintln!(
+                    "Upload hook for {} timed out after {} seconds and was killed",
+                    event.path.display(),
+                    self.timeout.as_secs()
+                );
+            }
+        }
+    }
+}
@@ -1,60 +1,337 @@
 use crate::os_specific::MetadataExtModified;
 
-use crate::AppState;
+use crate::{
+    acl::Permission,
+    archive::ArchiveFormat,
+    filter::{MatchMode, NameFilter, Visibility},
+    AppState,
+};
 use actix_files::NamedFile;
-use actix_web::{http::header::http_percent_encode, web, Either, HttpResponse};
-use serde::Serialize;
-use std::{
-    fmt,
-    path::{Path, PathBuf},
+use actix_web::{
+    http::{
+        header::{self, http_percent_encode},
+        Method,
+    },
+    web, Either, HttpRequest, HttpResponse,
 };
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::Path};
 
 #[derive(Serialize)]
-struct DirContent {
+pub struct DirContent {
     url: String,
     file_name: String,
+    /// Directory containing the entry relative to the listed one, for entries found by search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
     svg_icon: &'static str,
     date: String,
     size: String,
 }
 
+impl DirContent {
+    pub fn new(
+        file_name: String,
+        path: Option<String>,
+        metadata: &std::fs::Metadata,
+    ) -> std::io::Result<Self> {
+        let mut url = String::new();
+        if let Some(path) = &path {
+            for segment in path.split('/') {
+                url.push_str(&format!("{}/", PercentEncodedStr(segment)));
+            }
+        }
+        url.push_str(&format!("{}", PercentEncodedStr(&file_name)));
+
+        let svg_icon = if metadata.is_dir() {
+            url.push('/');
+            "folder"
+        } else {
+            "file"
+        };
+
+        Ok(DirContent {
+            url,
+            file_name,
+            path,
+            svg_icon,
+            date: metadata.modified_date()?,
+            size: stringify_file_size(metadata.len()),
+        })
+    }
+}
+
 #[derive(Serialize)]
-struct Breadcrumb {
+pub struct Breadcrumb {
     url: String,
     segment: String,
 }
 
+#[derive(Serialize)]
+struct Pagination {
+    page: usize,
+    pages: usize,
+    prev_url: Option<String>,
+    next_url: Option<String>,
+}
+
 #[derive(Serialize)]
 struct TemplateContext {
     breadcrumbs: Vec<Breadcrumb>,
     contents: Vec<DirContent>,
+    filter: Option<String>,
+    pagination: Option<Pagination>,
+    allow_delete: bool,
+    accept: Option<String>,
+    can_read: bool,
+    can_upload: bool,
+    can_change: bool,
+    can_share: bool,
+    /// Token that the forms need in a login session, which can also be ended from the page
+    csrf: Option<String>,
+    user: Option<String>,
+}
+
+impl TemplateContext {
+    /// Context for the listing of `dir`, showing only what the sender of `req` is allowed to do
+    /// there. Visitors of share links can only look.
+    fn new(
+        data: &AppState<'_>,
+        req: &HttpRequest,
+        dir: &Path,
+        breadcrumbs: Vec<Breadcrumb>,
+        contents: Vec<DirContent>,
+    ) -> Self {
+        let user = crate::auth::user(req);
+        let owns = crate::share::current(req).is_none();
+        let allows = |permission| crate::acl::allows(data, user.as_deref(), dir, permission);
+        TemplateContext {
+            breadcrumbs,
+            contents,
+            filter: None,
+            pagination: None,
+            allow_delete: owns
+                && data.allow_delete
+                && data.mode.can_change()
+                && allows(Permission::Delete),
+            accept: data.upload_types.accept(),
+            can_read: data.mode.can_read() && allows(Permission::List),
+            can_upload: owns && data.mode.can_upload() && allows(Permission::Upload),
+            can_change: owns && data.mode.can_change() && allows(Permission::Upload),
+            can_share: owns && data.mode.can_read() && allows(Permission::Read),
+            csrf: crate::session::current(req).map(|session| session.csrf_token),
+            user,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonListing {
+    contents: Vec<DirContent>,
+    total: usize,
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IndexQuery {
+    filter: Option<String>,
+    page: Option<usize>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    search: Option<String>,
+    grep: Option<String>,
+    #[serde(default)]
+    mode: MatchMode,
+    context: Option<usize>,
+    archive: Option<ArchiveFormat>,
+}
+
+const DEFAULT_PAGE_LIMIT: usize = 500;
+const MAX_PAGE_LIMIT: usize = 10_000;
+
+/// Which part of the sorted directory entries to list
+enum Window<'a> {
+    /// 1-based page number
+    Page(usize),
+    /// Entries sorting after the one named by the cursor
+    After(&'a str),
+}
+
+struct Listing {
+    contents: Vec<DirContent>,
+    /// Number of entries that passed the filter
+    total: usize,
+    /// Index of the first listed entry
+    start: usize,
+    next_cursor: Option<String>,
 }
 
 pub type IndexResponseType = Either<HttpResponse, Either<NamedFile, HttpResponse>>;
 
-pub async fn index(data: web::Data<AppState<'_>>, web_path: String) -> IndexResponseType {
+pub async fn index(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    web_path: String,
+) -> IndexResponseType {
     // Path on the server
-    let local_path = data.serve_from.join(&web_path);
-
-    match dir_contents(&local_path).await {
-        Ok(contents) => {
-            let breadcrumbs = {
-                let mut tmp = Vec::new();
-                let mut url = String::new();
-                for component in Path::new(&web_path).components().rev() {
-                    let segment = component.as_os_str().to_string_lossy().into_owned();
-                    tmp.push(Breadcrumb {
-                        url: url.clone(),
-                        segment,
-                    });
-                    url.push_str("../");
-                }
-                tmp.reverse();
-                tmp
+    let local_path = match data.local_path(&web_path) {
+        Some(local_path) => local_path,
+        None => return not_found(&data),
+    };
+
+    let user = crate::auth::user(req);
+    let is_dir = local_path.is_dir();
+
+    // Drop boxes only show the upload form
+    if !data.mode.can_read() {
+        if !is_dir {
+            return forbidden(&data);
+        }
+        let context =
+            TemplateContext::new(&data, req, &local_path, breadcrumbs(&web_path), Vec::new());
+        let body = data
+            .hbs
+            .render_template(crate::MAIN_TEMPLATE, &context)
+            .expect("Handlebars failed at rendering");
+        return Either::Left(HttpResponse::Ok().body(body));
+    }
+
+    let query = match web::Query::<IndexQuery>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
+        Err(_) => return bad_request(&data),
+    };
+
+    // Archives hold the contents of files, the rest only shows what is in the directory
+    let permission = if !is_dir || query.archive.is_some() {
+        Permission::Read
+    } else {
+        Permission::List
+    };
+    if !crate::acl::allows(&data, user.as_deref(), &local_path, permission) {
+        return forbidden(&data);
+    }
+    let visibility = crate::acl::visibility(&data, user.as_deref());
+
+    // Downloads through share links may be limited
+    if let Some(share) = crate::share::current(req) {
+        let is_download = permission == Permission::Read && req.method() == Method::GET;
+        if is_download {
+            if let Err(http_error) = data.shares.count_download(&share) {
+                return Either::Right(Either::Right(crate::error::error_response(
+                    &data.hbs, http_error,
+                )));
+            }
+        }
+    }
+
+    if let Some(pattern) = query.search.filter(|pattern| !pattern.is_empty()) {
+        if !local_path.is_dir() {
+            return not_found(&data);
+        }
+        let name_filter = match NameFilter::new(&pattern, query.mode) {
+            Ok(name_filter) => name_filter,
+            Err(_) => return bad_request(&data),
+        };
+        let breadcrumbs = breadcrumbs(&web_path);
+        return Either::Left(crate::search::search(
+            &data,
+            local_path,
+            breadcrumbs,
+            pattern,
+            name_filter,
+            visibility,
+        ));
+    }
+
+    if let Some(format) = query.archive {
+        if !local_path.is_dir() {
+            return not_found(&data);
+        }
+        let name = data.dir_name(&web_path);
+        return Either::Left(crate::archive::download(
+            local_path, name, format, visibility,
+        ));
+    }
+
+    if let Some(pattern) = query.grep.filter(|pattern| !pattern.is_empty()) {
+        if !local_path.is_dir() {
+            return not_found(&data);
+        }
+        let regex = match crate::search::content_regex(&pattern, query.mode) {
+            Ok(regex) => regex,
+            Err(_) => return bad_request(&data),
+        };
+        let context_lines = query
+            .context
+            .unwrap_or(crate::search::DEFAULT_CONTEXT_LINES)
+            .min(crate::search::MAX_CONTEXT_LINES);
+        let breadcrumbs = breadcrumbs(&web_path);
+        return Either::Left(crate::search::grep(
+            &data,
+            local_path,
+            breadcrumbs,
+            pattern,
+            regex,
+            context_lines,
+            visibility,
+        ));
+    }
+
+    let filter = query.filter.filter(|pattern| !pattern.is_empty());
+    let name_filter = match filter
+        .as_deref()
+        .map(|pattern| NameFilter::new(pattern, MatchMode::Auto))
+        .transpose()
+    {
+        Ok(name_filter) => name_filter,
+        Err(_) => return bad_request(&data),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let window = match query.cursor.as_deref() {
+        Some(cursor) => Window::After(cursor),
+        None => Window::Page(query.page.unwrap_or(1)),
+    };
+
+    match dir_contents(
+        &visibility,
+        &local_path,
+        name_filter.as_ref(),
+        window,
+        limit,
+    )
+    .await
+    {
+        Ok(listing) => {
+            if accepts_json(req) {
+                return Either::Left(HttpResponse::Ok().json(JsonListing {
+                    contents: listing.contents,
+                    total: listing.total,
+                    next_cursor: listing.next_cursor,
+                }));
+            }
+
+            let breadcrumbs = breadcrumbs(&web_path);
+            let pages = listing.total.div_ceil(limit);
+            let pagination = if pages > 1 {
+                let page = listing.start / limit + 1;
+                let page_url = |page| page_url(page, limit, filter.as_deref());
+                Some(Pagination {
+                    page,
+                    pages,
+                    prev_url: (page > 1).then(|| page_url(page - 1)),
+                    next_url: (page < pages).then(|| page_url(page + 1)),
+                })
+            } else {
+                None
             };
             let context = TemplateContext {
-                breadcrumbs,
-                contents,
+                filter,
+                pagination,
+                ..TemplateContext::new(&data, req, &local_path, breadcrumbs, listing.contents)
             };
             let body = data
                 .hbs
@@ -65,81 +342,191 @@ pub async fn index(data: web::Data<AppState<'_>>, web_path: String) -> IndexResp
         }
         Err(_) => match NamedFile::open_async(local_path).await {
             Ok(named_file) => Either::Right(Either::Left(named_file)),
-            Err(_) => Either::Right(Either::Right(crate::error::error_response(
-                &data.hbs,
-                crate::error::HttpError::NotFound,
-            ))),
+            Err(_) => not_found(&data),
         },
     }
 }
 
-async fn dir_contents(dir_path: &PathBuf) -> std::io::Result<Vec<DirContent>> {
-    // Helper struct for percent encoding a string
-    struct PercentEncodedStr<'a>(&'a str);
+pub fn breadcrumbs(web_path: &str) -> Vec<Breadcrumb> {
+    let mut breadcrumbs = Vec::new();
+    let mut url = String::new();
+    for component in Path::new(web_path).components().rev() {
+        let segment = component.as_os_str().to_string_lossy().into_owned();
+        breadcrumbs.push(Breadcrumb {
+            url: url.clone(),
+            segment,
+        });
+        url.push_str("../");
+    }
+    breadcrumbs.reverse();
+    breadcrumbs
+}
+
+/// Whether the client asked for a JSON response instead of HTML
+pub fn accepts_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
 
-    impl<'a> fmt::Display for PercentEncodedStr<'a> {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            http_percent_encode(f, self.0.as_bytes())
-        }
+fn page_url(page: usize, limit: usize, filter: Option<&str>) -> String {
+    let mut url = format!("?page={}", page);
+    if limit != DEFAULT_PAGE_LIMIT {
+        url.push_str(&format!("&limit={}", limit));
     }
+    if let Some(filter) = filter {
+        url.push_str("&filter=");
+        url.extend(utf8_percent_encode(filter, NON_ALPHANUMERIC));
+    }
+    url
+}
 
-    fn stringify_file_size(file_size: u64) -> String {
-        macro_rules! ldexp {
-            ($fp:expr, $exp:literal) => {
-                $fp * f64::from_bits(((0x3ff + $exp) as u64) << 52)
-            };
-        }
+fn bad_request(data: &web::Data<AppState<'_>>) -> IndexResponseType {
+    Either::Right(Either::Right(crate::error::error_response(
+        &data.hbs,
+        crate::error::HttpError::BadRequest,
+    )))
+}
+
+fn forbidden(data: &web::Data<AppState<'_>>) -> IndexResponseType {
+    Either::Right(Either::Right(crate::error::error_response(
+        &data.hbs,
+        crate::error::HttpError::Forbidden,
+    )))
+}
+
+fn not_found(data: &web::Data<AppState<'_>>) -> IndexResponseType {
+    Either::Right(Either::Right(crate::error::error_response(
+        &data.hbs,
+        crate::error::HttpError::NotFound,
+    )))
+}
+
+// Helper struct for percent encoding a string
+struct PercentEncodedStr<'a>(&'a str);
+
+impl<'a> fmt::Display for PercentEncodedStr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        http_percent_encode(f, self.0.as_bytes())
+    }
+}
 
-        const U32_MAX: u64 = u32::MAX as u64;
-
-        match file_size {
-            0..=U32_MAX => {
-                const KIB: u32 = 1 << 10;
-                const MIB: u32 = 1 << 20;
-                const MIB_M_1: u32 = MIB - 1;
-                const GIB: u32 = 1 << 30;
-                const GIB_M_1: u32 = GIB - 1;
-
-                let file_size = file_size as u32;
-                match file_size {
-                    0 => "".to_owned(),
-                    KIB..=MIB_M_1 => format!("{:.2} kiB", ldexp!(file_size as f64, -10)),
-                    MIB..=GIB_M_1 => format!("{:.2} MiB", ldexp!(file_size as f64, -20)),
-                    GIB..=u32::MAX => format!("{:.2} GiB", ldexp!(file_size as f64, -30)),
-                    _ => format!("{} B", file_size),
-                }
+fn stringify_file_size(file_size: u64) -> String {
+    macro_rules! ldexp {
+        ($fp:expr, $exp:literal) => {
+            $fp * f64::from_bits(((0x3ff + $exp) as u64) << 52)
+        };
+    }
+
+    const U32_MAX: u64 = u32::MAX as u64;
+
+    match file_size {
+        0..=U32_MAX => {
+            const KIB: u32 = 1 << 10;
+            const MIB: u32 = 1 << 20;
+            const MIB_M_1: u32 = MIB - 1;
+            const GIB: u32 = 1 << 30;
+            const GIB_M_1: u32 = GIB - 1;
+
+            let file_size = file_size as u32;
+            match file_size {
+                0 => "".to_owned(),
+                KIB..=MIB_M_1 => format!("{:.2} kiB", ldexp!(file_size as f64, -10)),
+                MIB..=GIB_M_1 => format!("{:.2} MiB", ldexp!(file_size as f64, -20)),
+                GIB..=u32::MAX => format!("{:.2} GiB", ldexp!(file_size as f64, -30)),
+                _ => format!("{} B", file_size),
             }
-            file_size => format!("{:.2} GiB", ldexp!(file_size as f64, -30)),
         }
+        file_size => format!("{:.2} GiB", ldexp!(file_size as f64, -30)),
     }
+}
 
-    let mut directories = vec![];
-    let mut files = vec![];
+/// Entries of `dir_path` that are visible under `visibility` and pass `name_filter`, directories
+/// first and then by name. Each entry is paired with whether it is not a directory and its file
+/// name.
+pub async fn sorted_entries(
+    visibility: &Visibility,
+    dir_path: &Path,
+    name_filter: Option<&NameFilter>,
+) -> std::io::Result<Vec<(bool, String, tokio::fs::DirEntry)>> {
+    // Only the file type is needed for sorting, which is usually known without a `stat`
+    let mut entries = vec![];
     let mut dir_reader = tokio::fs::read_dir(dir_path).await?;
     while let Some(entry) = dir_reader.next_entry().await? {
         let file_name = entry.file_name().to_string_lossy().into_owned();
-        let mut url = format!("{}", PercentEncodedStr(&file_name));
+        if visibility.hides(&entry.path()) {
+            continue;
+        }
+        if let Some(name_filter) = name_filter {
+            if !name_filter.is_match(&file_name) {
+                continue;
+            }
+        }
+        // Entries that can't be looked at are left out rather than failing the whole listing
+        let Ok(file_type) = entry.file_type().await else {
+            continue;
+        };
+        entries.push((!file_type.is_dir(), file_name, entry));
+    }
+    entries.sort_unstable_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    Ok(entries)
+}
 
-        let metadata = entry.metadata().await?;
+/// Lists the window of `dir_path` selected by `window`, only reading the metadata of the entries
+/// that end up in it.
+async fn dir_contents(
+    visibility: &Visibility,
+    dir_path: &Path,
+    name_filter: Option<&NameFilter>,
+    window: Window<'_>,
+    limit: usize,
+) -> std::io::Result<Listing> {
+    let mut entries = sorted_entries(visibility, dir_path, name_filter).await?;
 
-        let file_size = metadata.len();
-        let date = metadata.modified_date()?;
+    let total = entries.len();
+    let start = match window {
+        Window::Page(page) => {
+            let last_page_start = total.saturating_sub(1) / limit * limit;
+            page.saturating_sub(1)
+                .saturating_mul(limit)
+                .min(last_page_start)
+        }
+        Window::After(cursor) => {
+            // The cursor is the file name of the last entry seen, suffixed with '/' for directories
+            let key = match cursor.strip_suffix('/') {
+                Some(name) => (false, name),
+                None => (true, cursor),
+            };
+            entries.partition_point(|(is_file, file_name, _)| (*is_file, file_name.as_str()) <= key)
+        }
+    };
+    let end = total.min(start + limit);
+    // Taken from the last entry of the window, even when its metadata can't be read below
+    let next_cursor = (end < total).then(|| {
+        let (is_file, file_name, _) = &entries[end - 1];
+        let mut cursor = file_name.clone();
+        if !is_file {
+            cursor.push('/');
+        }
+        cursor
+    });
 
-        let (vec, svg_icon) = if metadata.is_dir() {
-            url.push('/');
-            (&mut directories, "folder")
-        } else {
-            (&mut files, "file")
+    let mut contents = Vec::with_capacity(end - start);
+    for (_, file_name, entry) in entries.drain(start..end) {
+        // Also left out when their metadata can't be read, like files locked by another process
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
         };
-
-        vec.push(DirContent {
-            url,
-            file_name,
-            svg_icon,
-            date,
-            size: stringify_file_size(file_size),
-        })
+        if let Ok(content) = DirContent::new(file_name, None, &metadata) {
+            contents.push(content);
+        }
     }
-    directories.append(&mut files);
-    Ok(directories)
+
+    Ok(Listing {
+        contents,
+        total,
+        start,
+        next_cursor,
+    })
 }
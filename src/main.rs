@@ -1,28 +1,95 @@
+mod acl;
+mod actions;
+mod archive;
+mod auth;
+mod delete;
 mod error;
+mod extract;
+mod filter;
+mod hook;
 mod index;
+mod mkdir;
+mod put;
+mod rename;
+mod search;
+mod session;
+mod share;
+mod signing;
 mod statics;
 mod tls_server_config;
+mod tus;
 mod upload;
+mod upload_types;
+mod webdav;
+mod zip;
 
 #[cfg(target_os = "windows")]
 #[path = "windows.rs"]
 mod os_specific;
 
 use actix_web::{
-    http::Method,
+    http::{header, Method},
     web::{self, Payload},
     App, Either, HttpRequest, HttpServer,
 };
 use aho_corasick::AhoCorasick;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use handlebars::Handlebars;
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 const MAIN_TEMPLATE: &str = include_str!("../templates/main.html.hbs");
 const ERROR_TEMPLATE: &str = include_str!("../templates/error.html.hbs");
+const SEARCH_TEMPLATE: &str = include_str!("../templates/search.html.hbs");
+const SHARE_TEMPLATE: &str = include_str!("../templates/share.html.hbs");
+const LOGIN_TEMPLATE: &str = include_str!("../templates/login.html.hbs");
+const DIR_ITEM_TEMPLATE: &str = include_str!("../templates/dir_item.html.hbs");
+const GREP_ITEM_TEMPLATE: &str = include_str!("../templates/grep_item.html.hbs");
+
+/// What clients can do: only look at files, also change them, or only upload. Drop boxes show
+/// the upload form instead of listings, refuse all downloads, including of files that the
+/// requester uploaded, and store uploads under new names rather than replace existing files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    ReadOnly,
+    ReadWrite,
+    DropBox,
+}
+
+impl Mode {
+    fn can_read(self) -> bool {
+        self != Mode::DropBox
+    }
+
+    fn can_upload(self) -> bool {
+        self != Mode::ReadOnly
+    }
+
+    fn can_change(self) -> bool {
+        self == Mode::ReadWrite
+    }
+
+    /// Refuses requests the mode doesn't allow. Form actions are checked by `actions` and what
+    /// drop boxes show by `index`, since that depends on more than the method.
+    fn check(self, req: &HttpRequest) -> Result<(), error::HttpError> {
+        let allowed = match *req.method() {
+            Method::GET | Method::HEAD | Method::OPTIONS => true,
+            Method::POST if is_form_submission(req) => true,
+            Method::POST | Method::PUT => self.can_upload(),
+            _ if req.method().as_str() == "PROPFIND" => self.can_read(),
+            _ => self.can_change(),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(error::HttpError::Forbidden)
+        }
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -42,17 +109,123 @@ struct Args {
     /// Enable/disable TLS
     #[arg(short, long, default_value_t = true)]
     tls: bool,
+
+    /// htpasswd file of the users that can log in. Passwords need to be hashed with bcrypt
+    /// (`htpasswd -B`) or Argon2. Browsers get a login form, other clients use HTTP Basic
+    /// authentication. Without it, anyone can connect
+    #[arg(long, value_name = "FILE")]
+    auth_file: Option<PathBuf>,
+
+    /// Name shown by browsers when asking for a user name and password
+    #[arg(long, value_name = "REALM", default_value = "File server")]
+    auth_realm: String,
+
+    /// Minutes without requests after which users that logged in with the login form have to log
+    /// in again
+    #[arg(long, value_name = "MINUTES", default_value_t = 60)]
+    session_idle_timeout: u64,
+
+    /// Hours after logging in with the login form at which users have to log in again, however
+    /// active they are
+    #[arg(long, value_name = "HOURS", default_value_t = 24)]
+    session_max_age: u64,
+
+    /// JSON file of rules for which users can read, list, upload and delete under which paths.
+    /// Without it, everyone that can connect can do everything the mode allows
+    #[arg(long, value_name = "FILE")]
+    acl_file: Option<PathBuf>,
+
+    /// What clients can do with the files. In a drop box clients can only upload, and nobody can
+    /// download any file through the server, not even the files they uploaded themselves
+    #[arg(long, value_enum, default_value_t = Mode::ReadWrite)]
+    mode: Mode,
+
+    /// Show hidden files (names starting with a dot) in listings and search results
+    #[arg(long)]
+    show_hidden: bool,
+
+    /// Glob for file names to leave out of listings, search results and archives. Can be given
+    /// multiple times
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Allow clients to delete files and directories
+    #[arg(long)]
+    allow_delete: bool,
+
+    /// Largest file that can be uploaded, in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_upload_size: Option<u64>,
+
+    /// What to do with uploads that have the same name as an existing file
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        default_value_t = upload::ConflictPolicy::Overwrite
+    )]
+    on_conflict: upload::ConflictPolicy,
+
+    /// Extension of files that can be uploaded, like `pdf`. Can be given multiple times; files
+    /// with other extensions are then refused
+    #[arg(long, value_name = "EXT")]
+    allow_extension: Vec<String>,
+
+    /// Extension of files that can't be uploaded. Can be given multiple times. Files whose
+    /// contents are recognized as such a kind of file are refused whatever their names
+    #[arg(long, value_name = "EXT")]
+    deny_extension: Vec<String>,
+
+    /// Type that the contents of uploaded files need to have, like `application/pdf` or
+    /// `image/*`. Can be given multiple times. Unrecognized contents are taken to be of the type
+    /// their extension suggests
+    #[arg(long, value_name = "MIME")]
+    allow_type: Vec<String>,
+
+    /// Program to run for every uploaded file, with the path of the file as its argument. The path
+    /// of the file, its path from the root, size, SHA-256 if known, how it was uploaded (form, put
+    /// or tus) and who uploaded it if users log in are in the UPLOAD_PATH, UPLOAD_WEB_PATH,
+    /// UPLOAD_SIZE, UPLOAD_SHA256, UPLOAD_SOURCE and UPLOAD_USER environment variables
+    #[arg(long, value_name = "COMMAND")]
+    on_upload: Option<PathBuf>,
+
+    /// Seconds after which the upload program is killed
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    on_upload_timeout: u64,
+
+    /// How many upload programs can run at the same time. Up to 100 more wait for their turn, and
+    /// uploads beyond that don't get the program run for them.
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    on_upload_jobs: usize,
+
+    /// Directory for unfinished resumable uploads [default: a directory in the system's temporary
+    /// directory]
+    #[arg(long, value_name = "DIR")]
+    resumable_dir: Option<PathBuf>,
 }
 
 pub struct AppState<'reg> {
     serve_from: PathBuf,
+    auth: Option<auth::Htpasswd>,
+    acl: Option<Arc<acl::Acl>>,
+    mode: Mode,
+    visibility: filter::Visibility,
+    allow_delete: bool,
+    max_upload_size: Option<u64>,
+    on_conflict: upload::ConflictPolicy,
+    upload_types: upload_types::UploadTypes,
+    on_upload: Option<hook::UploadHook>,
+    dav: webdav::DavState,
+    tus: tus::TusState,
+    shares: share::ShareState,
+    sessions: session::Sessions,
     hbs: Handlebars<'reg>,
     ac: AhoCorasick,
 }
 
 impl<'reg> AppState<'reg> {
-    fn new(serve_from: &str) -> Self {
-        let serve_from = PathBuf::from(serve_from)
+    fn new(args: &Args) -> Self {
+        let serve_from = PathBuf::from(&args.root)
             .canonicalize()
             .expect("Unable to canonicalize root directory");
         if !serve_from.is_dir() {
@@ -82,12 +255,113 @@ impl<'reg> AppState<'reg> {
         //     include_static_file!("layout", "css"),
         // ];
 
+        let mut hbs = Handlebars::new();
+        hbs.register_partial("dir_item", DIR_ITEM_TEMPLATE)
+            .expect("Invalid dir_item template");
+
         Self {
             serve_from,
-            hbs: Handlebars::new(),
+            auth: args.auth_file.as_ref().map(|path| {
+                auth::Htpasswd::load(path, &args.auth_realm).expect("Unable to load the auth file")
+            }),
+            acl: args
+                .acl_file
+                .as_ref()
+                .map(|path| Arc::new(acl::Acl::load(path).expect("Unable to load the ACL file"))),
+            mode: args.mode,
+            visibility: filter::Visibility::new(args.show_hidden, &args.exclude)
+                .expect("Invalid exclude pattern"),
+            allow_delete: args.allow_delete,
+            max_upload_size: args.max_upload_size,
+            on_conflict: args.on_conflict,
+            upload_types: upload_types::UploadTypes::new(
+                &args.allow_extension,
+                &args.deny_extension,
+                &args.allow_type,
+            ),
+            on_upload: args.on_upload.clone().map(|command| {
+                hook::UploadHook::new(
+                    command,
+                    Duration::from_secs(args.on_upload_timeout),
+                    args.on_upload_jobs,
+                )
+            }),
+            dav: webdav::DavState::default(),
+            tus: tus::TusState::new(
+                args.resumable_dir
+                    .clone()
+                    .unwrap_or_else(|| std::env::temp_dir().join("file-server-rs-uploads")),
+            )
+            .expect("Unable to create the directory for resumable uploads"),
+            shares: share::ShareState::new(),
+            sessions: session::Sessions::new(
+                Duration::from_secs(args.session_idle_timeout * 60),
+                Duration::from_secs(args.session_max_age * 60 * 60),
+            ),
+            hbs,
             ac: statics::build_aho_corasick(),
         }
     }
+
+    /// Maps a decoded request path to a path on the server, rejecting anything that could escape
+    /// the served directory.
+    fn local_path(&self, web_path: &str) -> Option<PathBuf> {
+        let mut local_path = self.serve_from.clone();
+        for component in Path::new(web_path).components() {
+            match component {
+                Component::Normal(segment) => local_path.push(segment),
+                Component::CurDir => (),
+                _ => return None,
+            }
+        }
+        Some(local_path)
+    }
+
+    /// Maps a URL, either absolute or just its path, to a path on the server. Used for the URLs
+    /// WebDAV clients send in headers.
+    fn local_path_from_url(&self, url: &str) -> Option<PathBuf> {
+        let path = match url.split_once("://") {
+            Some((_, rest)) => &rest[rest.find('/')?..],
+            None => url,
+        };
+        // Queries and fragments are not part of the path
+        let path = path.split(['?', '#']).next()?;
+        let path = percent_encoding::percent_decode_str(path.trim_start_matches('/'))
+            .decode_utf8()
+            .ok()?;
+        self.local_path(&path)
+    }
+
+    /// Path from the root of a path on the server, starting with a slash. The reverse of
+    /// `local_path`.
+    fn web_path(&self, local_path: &Path) -> String {
+        web_path(&self.serve_from, local_path)
+    }
+
+    /// Name of the directory at `web_path`, as shown in its breadcrumb
+    fn dir_name(&self, web_path: &str) -> String {
+        match Path::new(web_path)
+            .file_name()
+            .or(self.serve_from.file_name())
+        {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => "root".to_owned(),
+        }
+    }
+}
+
+/// Path of `local_path` from `serve_from`, starting with a slash
+fn web_path(serve_from: &Path, local_path: &Path) -> String {
+    let relative_path = local_path.strip_prefix(serve_from).unwrap_or(local_path);
+    let mut web_path = String::new();
+    for component in relative_path.components() {
+        web_path.push('/');
+        web_path.push_str(&component.as_os_str().to_string_lossy());
+    }
+    if web_path.is_empty() {
+        web_path.push('/');
+    }
+    web_path
 }
 
 pub async fn catch_all(
@@ -102,18 +376,53 @@ pub async fn catch_all(
         .decode_utf8_lossy()
         .to_string();
 
+    // Conditions in the `If` header and WebDAV locks apply to every method
+    if let Err(http_error) = data
+        .mode
+        .check(&req)
+        .and_then(|()| share::check_request(&req))
+        .and_then(|()| session::check_request(&data, &req))
+        .and_then(|()| acl::check_request(&data, &req, &path))
+        .and_then(|()| webdav::check_request(&data, &req, &path))
+    {
+        return Either::Right(error::error_response(&data.hbs, http_error));
+    }
+
     match *req.method() {
-        Method::GET => Either::Left(index::index(data, path).await),
+        Method::GET | Method::HEAD => Either::Left(index::index(data, &req, path).await),
+        Method::OPTIONS => Either::Right(webdav::options()),
+        Method::POST if is_form_submission(&req) => {
+            Either::Right(actions::form_action(data, req, payload, path).await)
+        }
         Method::POST => Either::Right(upload::upload(data, req, payload, path).await),
-        _ => todo!(),
+        Method::PUT => Either::Right(put::put(data, &req, payload, path).await),
+        Method::DELETE => Either::Right(delete::delete(data, &req, path).await),
+        _ => Either::Right(match req.method().as_str() {
+            "MKCOL" => mkdir::mkcol(data, &req, path).await,
+            "MOVE" => rename::move_request(data, &req, path).await,
+            "COPY" => rename::copy_request(data, &req, path).await,
+            "PROPFIND" => webdav::propfind(data, &req, payload, path).await,
+            "PROPPATCH" => webdav::proppatch(data, &req, payload, path).await,
+            "LOCK" => webdav::lock(data, &req, payload, path).await,
+            "UNLOCK" => webdav::unlock(data, &req, path),
+            _ => error::error_response(&data.hbs, error::HttpError::MethodNotAllowed),
+        }),
     }
 }
 
+/// Forms other than the upload form are sent URL-encoded
+fn is_form_submission(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
-    let app_state = AppState::new(&args.root);
+    let app_state = AppState::new(&args);
     let app_state_ref = web::Data::new(app_state);
 
     let mut ip_addr = args.addr;
@@ -138,7 +447,31 @@ async fn main() -> std::io::Result<()> {
     let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state_ref.clone())
+            .wrap(auth::BasicAuth)
             .service(statics::serve_static_file)
+            .service(
+                web::resource(["/.tus", "/.tus/"])
+                    .route(web::post().to(tus::create))
+                    .route(web::method(Method::OPTIONS).to(tus::options)),
+            )
+            .service(
+                web::resource("/.tus/{id}")
+                    .route(web::head().to(tus::head))
+                    .route(web::patch().to(tus::patch))
+                    .route(web::delete().to(tus::terminate))
+                    .route(web::method(Method::OPTIONS).to(tus::options)),
+            )
+            .service(
+                web::resource("/.share/{token}")
+                    .route(web::get().to(share::open))
+                    .route(web::post().to(share::unlock)),
+            )
+            .service(
+                web::resource("/.login")
+                    .route(web::get().to(session::login_form))
+                    .route(web::post().to(session::login)),
+            )
+            .service(web::resource("/.logout").route(web::post().to(session::logout)))
             .default_service(web::to(catch_all))
     });
 
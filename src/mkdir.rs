@@ -0,0 +1,95 @@
+use crate::{
+    acl::Permission,
+    error::{error_response, HttpError},
+    webdav::LockTokens,
+    AppState,
+};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Handles WebDAV `MKCOL` requests, creating the directory at `web_path`. Paths that already
+/// exist can't be made into a directory, so the method isn't allowed there.
+pub async fn mkcol(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    web_path: String,
+) -> HttpResponse {
+    // Bodies describing the contents of the new directory are not supported
+    let has_body = req.headers().contains_key(header::TRANSFER_ENCODING)
+        || req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .is_some_and(|length| length.as_bytes() != b"0");
+    if has_body {
+        return error_response(&data.hbs, HttpError::UnsupportedMediaType);
+    }
+
+    let path = Path::new(&web_path);
+    let result = match (
+        path.parent(),
+        path.file_name().and_then(|name| name.to_str()),
+    ) {
+        // Names are not sanitized here since the client expects the exact path to exist
+        (Some(parent), Some(name)) if sanitize_filename::sanitize(name) == name => {
+            match data.local_path(&parent.to_string_lossy()) {
+                Some(parent) if tokio::fs::symlink_metadata(parent.join(name)).await.is_ok() => {
+                    Err(HttpError::MethodNotAllowed)
+                }
+                Some(parent) => {
+                    let user = crate::auth::user(req);
+                    let tokens = LockTokens::of(req);
+                    create_dir(&data, &parent, name, user.as_deref(), &tokens).await
+                }
+                None => Err(HttpError::BadRequest),
+            }
+        }
+        (Some(_), Some(_)) => Err(HttpError::BadRequest),
+        // The root already exists
+        _ => Err(HttpError::MethodNotAllowed),
+    };
+
+    match result {
+        Ok(_) => HttpResponse::Created().finish(),
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+/// Creates a directory called `name` inside `parent` after sanitizing the name, returning the
+/// path of the new directory. The access rules and locks are checked for the sanitized name,
+/// since that is the directory that gets created.
+pub async fn create_dir(
+    data: &AppState<'_>,
+    parent: &Path,
+    name: &str,
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<PathBuf, HttpError> {
+    let name = sanitize_filename::sanitize(name.trim());
+    if name.is_empty() || name == "." || name == ".." || data.visibility.is_hidden(&name) {
+        return Err(HttpError::BadRequest);
+    }
+
+    let local_path = parent.join(name);
+    crate::acl::check(data, user, &local_path, Permission::Upload)?;
+    data.dav.check_writable(&local_path, false, true, tokens)?;
+    match tokio::fs::create_dir(&local_path).await {
+        Ok(()) => Ok(local_path),
+        // Missing parents are a conflict too, as in WebDAV
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::AlreadyExists | io::ErrorKind::NotFound
+            ) =>
+        {
+            Err(HttpError::Conflict)
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(HttpError::Forbidden),
+        Err(e) => {
+            eprintln!("Mkdir error: {}", e);
+            Err(HttpError::InternalServerError)
+        }
+    }
+}
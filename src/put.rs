@@ -0,0 +1,123 @@
+use crate::{
+    error::{error_response, HttpError},
+    rename::Placed,
+    upload::FileDetails,
+    AppState,
+};
+use actix_web::{
+    http::header::{self, LOCATION},
+    web::{self, Payload},
+    HttpRequest, HttpResponse,
+};
+use std::path::Path;
+
+/// Handles `PUT` requests, storing the raw body as the file at `web_path`. Answers with 201 for
+/// new files and 204 for replaced ones, and with the URL of the stored file in `Location`, which
+/// differs from the requested one if the upload was renamed because of a conflict.
+///
+/// `If-None-Match: *` keeps an existing file from being replaced. A SHA-256 in `Content-Digest`
+/// or `Digest` is checked against the body, which is discarded if it doesn't match.
+/// `X-File-Mtime` sets the modification time of the file, in seconds since the Unix epoch.
+pub async fn put(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    payload: Option<Payload>,
+    web_path: String,
+) -> HttpResponse {
+    let result = match payload {
+        Some(payload) => store_body(&data, req, payload, &web_path).await,
+        None => Err(HttpError::BadRequest),
+    };
+    match result {
+        Ok((location, placed)) => {
+            let mut builder = match placed {
+                Placed::Created => HttpResponse::Created(),
+                Placed::Overwritten => HttpResponse::NoContent(),
+            };
+            builder.insert_header((LOCATION, location)).finish()
+        }
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+/// Stores the body, returning the URL of the file and whether it replaced another one
+async fn store_body(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    payload: Payload,
+    web_path: &str,
+) -> Result<(String, Placed), HttpError> {
+    let path = Path::new(web_path);
+    let (parent, name) = match (
+        path.parent(),
+        path.file_name().and_then(|name| name.to_str()),
+    ) {
+        (Some(parent), Some(name)) => (parent, name),
+        // The root is a directory
+        _ => return Err(HttpError::MethodNotAllowed),
+    };
+    // Names are not sanitized here since the client expects the exact path to exist
+    if sanitize_filename::sanitize(name) != name || data.visibility.is_hidden(name) {
+        return Err(HttpError::BadRequest);
+    }
+    let parent_path = parent.to_string_lossy();
+    let dir = data.local_path(&parent_path).ok_or(HttpError::BadRequest)?;
+    // Missing parents are a conflict, as in WebDAV
+    if !dir.is_dir() {
+        return Err(HttpError::Conflict);
+    }
+    if dir.join(name).is_dir() {
+        return Err(HttpError::MethodNotAllowed);
+    }
+
+    // Oversized bodies are refused before reading them if the client says how large they are
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if let (Some(content_length), Some(max_size)) = (content_length, data.max_upload_size) {
+        if content_length > max_size {
+            return Err(HttpError::PayloadTooLarge);
+        }
+    }
+    let details = FileDetails {
+        sha256: crate::upload::expected_digest(req)?,
+        modified: crate::upload::modified_header(req)?,
+        extract: false,
+    };
+    let if_none_match_any = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == b"*");
+    if if_none_match_any && std::fs::symlink_metadata(dir.join(name)).is_ok() {
+        return Err(HttpError::PreconditionFailed);
+    }
+
+    // Bodies cut short by a disconnect end in an error, so nothing partial is stored
+    let stored = crate::upload::store(
+        data,
+        &dir,
+        name,
+        payload.into_inner(),
+        &details,
+        &crate::webdav::LockTokens::of(req),
+    )
+    .await?;
+    crate::upload::run_hook(
+        data,
+        &stored.path,
+        stored.size,
+        Some(&stored.sha256),
+        "put",
+        crate::auth::user(req).as_deref(),
+    );
+
+    let stored_name = stored
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let location = crate::webdav::href(&format!("{}/{}", parent_path, stored_name), false);
+    Ok((location, stored.placed))
+}
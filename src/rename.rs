@@ -0,0 +1,271 @@
+use crate::{
+    error::{error_response, HttpError},
+    webdav::LockTokens,
+    AppState,
+};
+use actix_web::{rt::task, web, HttpRequest, HttpResponse};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// What happened at the destination of a move or copy
+pub enum Placed {
+    Created,
+    Overwritten,
+}
+
+/// Handles WebDAV `MOVE` requests. The target is given by the `Destination` header and is
+/// replaced unless the request has `Overwrite: F`.
+pub async fn move_request(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    web_path: String,
+) -> HttpResponse {
+    let result = match (data.local_path(&web_path), destination(&data, req)) {
+        (Some(from), Some(to)) => {
+            let tokens = LockTokens::of(req);
+            move_path(&data, &from, &to, overwrite(req), &tokens).await
+        }
+        (None, _) => Err(HttpError::NotFound),
+        (_, None) => Err(HttpError::BadRequest),
+    };
+    placed_response(&data, result)
+}
+
+/// Handles WebDAV `COPY` requests. Directories are copied with everything in them unless the
+/// request has `Depth: 0`.
+pub async fn copy_request(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    web_path: String,
+) -> HttpResponse {
+    let recursive = match req.headers().get("Depth").map(|depth| depth.as_bytes()) {
+        None => true,
+        Some(depth) if depth.eq_ignore_ascii_case(b"infinity") => true,
+        Some(b"0") => false,
+        Some(_) => return error_response(&data.hbs, HttpError::BadRequest),
+    };
+    let result = match (data.local_path(&web_path), destination(&data, req)) {
+        (Some(from), Some(to)) => {
+            let tokens = LockTokens::of(req);
+            copy_path(&data, &from, &to, overwrite(req), recursive, &tokens).await
+        }
+        (None, _) => Err(HttpError::NotFound),
+        (_, None) => Err(HttpError::BadRequest),
+    };
+    placed_response(&data, result)
+}
+
+pub fn placed_response(data: &AppState<'_>, result: Result<Placed, HttpError>) -> HttpResponse {
+    match result {
+        Ok(Placed::Created) => HttpResponse::Created().finish(),
+        Ok(Placed::Overwritten) => HttpResponse::NoContent().finish(),
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+/// Local path named by the `Destination` header
+pub fn destination(data: &AppState<'_>, req: &HttpRequest) -> Option<PathBuf> {
+    let destination = req.headers().get("Destination")?.to_str().ok()?;
+    data.local_path_from_url(destination)
+}
+
+/// Whether the request allows replacing the target, which it does unless it has `Overwrite: F`
+pub fn overwrite(req: &HttpRequest) -> bool {
+    !req.headers()
+        .get("Overwrite")
+        .is_some_and(|overwrite| overwrite.as_bytes().eq_ignore_ascii_case(b"F"))
+}
+
+/// Moves a file or directory to `to`, copying and deleting it if it is on another filesystem.
+/// An existing target is only replaced if `overwrite` is set, and is kept if the move fails.
+/// Locks on either side need their tokens in `tokens`.
+pub async fn move_path(
+    data: &AppState<'_>,
+    from: &Path,
+    to: &Path,
+    overwrite: bool,
+    tokens: &LockTokens,
+) -> Result<Placed, HttpError> {
+    data.dav.check_writable(from, true, true, tokens)?;
+    data.dav.check_writable(to, true, true, tokens)?;
+    let target = prepare_target(data, from, to, overwrite).await?;
+    let moved = match tokio::fs::rename(from, to).await {
+        Ok(()) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let (from, to) = (from.to_owned(), to.to_owned());
+            run_blocking(move || copy_recursive(&from, &to))
+                .await
+                .map(|()| true)
+        }
+        Err(e) => Err(io_error(e)),
+    };
+    let placed = target.finish(to, moved.is_ok()).await;
+    if moved? {
+        // The original is only deleted once its copy is in place
+        let from = from.to_owned();
+        run_blocking(move || {
+            if from.is_dir() {
+                std::fs::remove_dir_all(&from)
+            } else {
+                std::fs::remove_file(&from)
+            }
+        })
+        .await?;
+    }
+    data.dav.moved(from, to);
+    Ok(placed)
+}
+
+/// Copies a file or directory to `to`, leaving out the contents of directories unless
+/// `recursive` is set. An existing target is only replaced if `overwrite` is set, and is kept if
+/// the copy fails. Locks on the target need their tokens in `tokens`.
+pub async fn copy_path(
+    data: &AppState<'_>,
+    from: &Path,
+    to: &Path,
+    overwrite: bool,
+    recursive: bool,
+    tokens: &LockTokens,
+) -> Result<Placed, HttpError> {
+    data.dav.check_writable(to, true, true, tokens)?;
+    let target = prepare_target(data, from, to, overwrite).await?;
+    let (from_owned, to_owned) = (from.to_owned(), to.to_owned());
+    let copied = run_blocking(move || {
+        if !recursive && from_owned.is_dir() {
+            std::fs::create_dir(&to_owned)
+        } else {
+            copy_recursive(&from_owned, &to_owned)
+        }
+    })
+    .await;
+    let placed = target.finish(to, copied.is_ok()).await;
+    copied?;
+    data.dav.copied(from, to);
+    Ok(placed)
+}
+
+async fn run_blocking<F>(f: F) -> Result<(), HttpError>
+where
+    F: FnOnce() -> io::Result<()> + Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(io_error(e)),
+        Err(_) => Err(HttpError::InternalServerError),
+    }
+}
+
+/// Target of a move or copy. Whatever was there is moved aside until it is known whether the
+/// move or copy worked, so that it isn't lost if it didn't.
+pub struct Target {
+    placed: Placed,
+    replaced: Option<PathBuf>,
+}
+
+impl Target {
+    /// Deletes what was replaced at `to` if the move or copy `succeeded`, or puts it back
+    async fn finish(self, to: &Path, succeeded: bool) -> Placed {
+        let Some(replaced) = self.replaced else {
+            return self.placed;
+        };
+        if succeeded {
+            if let Err(e) = remove(&replaced).await {
+                eprintln!("Unable to delete {}: {}", replaced.display(), e);
+            }
+        } else {
+            // Whatever the move or copy left behind is in the way
+            let _ = remove(to).await;
+            if let Err(e) = tokio::fs::rename(&replaced, to).await {
+                eprintln!(
+                    "Unable to put {} back after a failed move or copy: {}",
+                    to.display(),
+                    e
+                );
+            }
+        }
+        self.placed
+    }
+}
+
+async fn remove(path: &Path) -> io::Result<()> {
+    if tokio::fs::symlink_metadata(path).await?.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    }
+}
+
+/// Checks that `from` can be moved or copied to `to`, moving whatever is at `to` aside if
+/// `overwrite` is set.
+pub async fn prepare_target(
+    data: &AppState<'_>,
+    from: &Path,
+    to: &Path,
+    overwrite: bool,
+) -> Result<Target, HttpError> {
+    if from == data.serve_from || to == data.serve_from {
+        return Err(HttpError::Forbidden);
+    }
+    if tokio::fs::symlink_metadata(from).await.is_err() {
+        return Err(HttpError::NotFound);
+    }
+    // A directory cannot end up inside itself
+    if to.starts_with(from) {
+        return Err(HttpError::Conflict);
+    }
+    let (parent, name) = match (to.parent(), to.file_name()) {
+        (Some(parent), Some(name)) if parent.is_dir() => (parent, name),
+        _ => return Err(HttpError::Conflict),
+    };
+
+    match tokio::fs::symlink_metadata(to).await {
+        Err(_) => Ok(Target {
+            placed: Placed::Created,
+            replaced: None,
+        }),
+        Ok(_) if !overwrite => Err(HttpError::PreconditionFailed),
+        Ok(_) => {
+            // Renaming within the directory can't cross filesystems, so it doesn't copy anything
+            let replaced = parent.join(format!(
+                ".{}.{}.replaced",
+                name.to_string_lossy(),
+                uuid::Uuid::new_v4()
+            ));
+            tokio::fs::rename(to, &replaced).await.map_err(io_error)?;
+            Ok(Target {
+                placed: Placed::Overwritten,
+                replaced: Some(replaced),
+            })
+        }
+    }
+}
+
+/// Copies a file, or a directory with everything in it. Symlinks are left out.
+pub fn copy_recursive(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = std::fs::symlink_metadata(from)?;
+    if metadata.is_dir() {
+        std::fs::create_dir(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else if metadata.is_file() {
+        std::fs::copy(from, to).map(|_| ())
+    } else {
+        Ok(())
+    }
+}
+
+fn io_error(e: io::Error) -> HttpError {
+    match e.kind() {
+        io::ErrorKind::NotFound => HttpError::Conflict,
+        io::ErrorKind::PermissionDenied => HttpError::Forbidden,
+        _ => {
+            eprintln!("Move or copy error: {}", e);
+            HttpError::InternalServerError
+        }
+    }
+}
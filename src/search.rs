@@ -0,0 +1,293 @@
+use crate::{
+    filter::{MatchMode, NameFilter, Visibility},
+    index::{Breadcrumb, DirContent},
+    AppState,
+};
+use actix_web::{
+    http::header::ContentType,
+    rt::task,
+    web::{self, Bytes},
+    HttpResponse,
+};
+use futures_util::{future, stream, StreamExt};
+use handlebars::Handlebars;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use std::{
+    convert::Infallible,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+/// Name searches stop after this many matches
+const MAX_RESULTS: usize = 1000;
+
+/// Content searches stop after this many matching lines
+const MAX_MATCHING_LINES: usize = 500;
+
+/// Files larger than this are skipped by content searches
+const MAX_GREP_FILE_SIZE: u64 = 8 << 20;
+
+/// Files with a NUL byte this close to the start are assumed to be binary
+const BINARY_SNIFF_LEN: usize = 8 << 10;
+
+/// Longest line shown in content search results, in characters
+const MAX_LINE_LEN: usize = 500;
+
+pub const DEFAULT_CONTEXT_LINES: usize = 2;
+pub const MAX_CONTEXT_LINES: usize = 10;
+
+/// Closes the tags left open by the search template
+const SEARCH_TAIL: &str = "    </div>\n  </body>\n</html>\n";
+
+#[derive(Serialize)]
+struct SearchTemplateContext {
+    breadcrumbs: Vec<Breadcrumb>,
+    /// Query parameter of the search box
+    param: &'static str,
+    placeholder: &'static str,
+    pattern: String,
+}
+
+#[derive(Serialize)]
+struct GrepLine {
+    number: usize,
+    text: String,
+    is_match: bool,
+    /// Lines were skipped between this and the previous shown line
+    gap_before: bool,
+}
+
+#[derive(Serialize)]
+struct GrepResult {
+    file: DirContent,
+    lines: Vec<GrepLine>,
+}
+
+/// Walks the tree under `dir_path` and streams every entry whose name matches `name_filter`,
+/// rendered the same way as the rows of a directory listing.
+pub fn search(
+    data: &web::Data<AppState<'_>>,
+    dir_path: PathBuf,
+    breadcrumbs: Vec<Breadcrumb>,
+    pattern: String,
+    name_filter: NameFilter,
+    visibility: Visibility,
+) -> HttpResponse {
+    let context = SearchTemplateContext {
+        breadcrumbs,
+        param: "search",
+        placeholder: "Search",
+        pattern,
+    };
+
+    stream_results(data, &context, move |hbs, tx| {
+        let mut num_results = 0;
+        for entry in walk(&dir_path, visibility) {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if !name_filter.is_match(&file_name) {
+                continue;
+            }
+
+            let dir_content =
+                match entry
+                    .metadata()
+                    .map_err(std::io::Error::from)
+                    .and_then(|metadata| {
+                        DirContent::new(
+                            file_name,
+                            relative_parent(&dir_path, entry.path()),
+                            &metadata,
+                        )
+                    }) {
+                    Ok(dir_content) => dir_content,
+                    Err(_) => continue,
+                };
+
+            let row = hbs
+                .render_template(crate::DIR_ITEM_TEMPLATE, &dir_content)
+                .expect("Handlebars failed at rendering");
+            // The receiver is gone if the client disconnected
+            if tx.blocking_send(row).is_err() {
+                break;
+            }
+
+            num_results += 1;
+            if num_results == MAX_RESULTS {
+                break;
+            }
+        }
+    })
+}
+
+/// Builds the matcher for a content search. Anything but a regex is searched for literally.
+pub fn content_regex(pattern: &str, mode: MatchMode) -> Result<Regex, regex::Error> {
+    let pattern = match mode {
+        MatchMode::Regex => pattern.to_owned(),
+        _ => regex::escape(pattern),
+    };
+    RegexBuilder::new(&pattern).case_insensitive(true).build()
+}
+
+/// Walks the tree under `dir_path` and streams the lines of text files that match `regex`,
+/// together with `context_lines` lines around each of them.
+pub fn grep(
+    data: &web::Data<AppState<'_>>,
+    dir_path: PathBuf,
+    breadcrumbs: Vec<Breadcrumb>,
+    pattern: String,
+    regex: Regex,
+    context_lines: usize,
+    visibility: Visibility,
+) -> HttpResponse {
+    let context = SearchTemplateContext {
+        breadcrumbs,
+        param: "grep",
+        placeholder: "Search contents",
+        pattern,
+    };
+
+    stream_results(data, &context, move |hbs, tx| {
+        let mut num_matching_lines = 0;
+        for entry in walk(&dir_path, visibility) {
+            let metadata = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() && metadata.len() <= MAX_GREP_FILE_SIZE => {
+                    metadata
+                }
+                _ => continue,
+            };
+            let contents = match read_text_file(entry.path()) {
+                Some(contents) => contents,
+                None => continue,
+            };
+
+            let lines: Vec<&str> = contents.lines().collect();
+            let mut shown_lines = Vec::new();
+            let mut last_shown: Option<usize> = None;
+            for (i, line) in lines.iter().enumerate() {
+                if !regex.is_match(line) {
+                    continue;
+                }
+                let start = i.saturating_sub(context_lines);
+                let end = lines.len().min(i + context_lines + 1);
+                for (j, shown_line) in lines.iter().enumerate().take(end).skip(start) {
+                    if last_shown.is_some_and(|last_shown| j <= last_shown) {
+                        continue;
+                    }
+                    shown_lines.push(GrepLine {
+                        number: j + 1,
+                        text: shown_line.chars().take(MAX_LINE_LEN).collect(),
+                        is_match: regex.is_match(shown_line),
+                        gap_before: last_shown.is_some_and(|last_shown| j > last_shown + 1),
+                    });
+                    last_shown = Some(j);
+                }
+
+                num_matching_lines += 1;
+                if num_matching_lines == MAX_MATCHING_LINES {
+                    break;
+                }
+            }
+            if shown_lines.is_empty() {
+                continue;
+            }
+
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let file = match DirContent::new(
+                file_name,
+                relative_parent(&dir_path, entry.path()),
+                &metadata,
+            ) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            let result = GrepResult {
+                file,
+                lines: shown_lines,
+            };
+            let html = hbs
+                .render_template(crate::GREP_ITEM_TEMPLATE, &result)
+                .expect("Handlebars failed at rendering");
+            if tx.blocking_send(html).is_err() || num_matching_lines == MAX_MATCHING_LINES {
+                break;
+            }
+        }
+    })
+}
+
+/// Renders the head of the search page and streams whatever `producer` sends after it. The
+/// producer runs on a blocking thread.
+fn stream_results<F>(
+    data: &web::Data<AppState<'_>>,
+    context: &SearchTemplateContext,
+    producer: F,
+) -> HttpResponse
+where
+    F: FnOnce(&Handlebars<'static>, &mpsc::Sender<String>) + Send + 'static,
+{
+    let head = data
+        .hbs
+        .render_template(crate::SEARCH_TEMPLATE, context)
+        .expect("Handlebars failed at rendering");
+
+    let (tx, rx) = mpsc::channel(64);
+    task::spawn_blocking(move || {
+        let mut hbs = Handlebars::new();
+        hbs.register_partial("dir_item", crate::DIR_ITEM_TEMPLATE)
+            .expect("Invalid dir_item template");
+        producer(&hbs, &tx);
+    });
+
+    let results = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|html| (html, rx))
+    });
+    let body = stream::once(future::ready(head))
+        .chain(results)
+        .chain(stream::once(future::ready(SEARCH_TAIL.to_owned())))
+        .map(|html| Ok::<_, Infallible>(Bytes::from(html)));
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::html())
+        .streaming(body)
+}
+
+/// Every visible entry under `dir_path`. Symlinks are not followed so that the walk cannot leave
+/// the served directory.
+fn walk(dir_path: &Path, visibility: Visibility) -> impl Iterator<Item = walkdir::DirEntry> {
+    WalkDir::new(dir_path)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(move |entry| !visibility.hides(entry.path()))
+        .filter_map(Result::ok)
+}
+
+/// The directory containing `path` relative to `dir_path`, or `None` if it is `dir_path` itself
+fn relative_parent(dir_path: &Path, path: &Path) -> Option<String> {
+    path.parent()
+        .and_then(|parent| parent.strip_prefix(dir_path).ok())
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .map(|parent| {
+            parent
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+}
+
+/// Reads the file if it does not look binary
+fn read_text_file(path: &Path) -> Option<String> {
+    let mut bytes = Vec::new();
+    std::fs::File::open(path)
+        .ok()?
+        .take(MAX_GREP_FILE_SIZE)
+        .read_to_end(&mut bytes)
+        .ok()?;
+    if bytes[..bytes.len().min(BINARY_SNIFF_LEN)].contains(&0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
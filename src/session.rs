@@ -0,0 +1,284 @@
+//! Logging in with a form, for browsers, as an alternative to HTTP Basic authentication. Logging
+//! in starts a session kept in memory, which a signed cookie refers to. A session ends when its
+//! user logs out, after a while without requests, or at the latest some time after logging in.
+//!
+//! Requests in a session that change anything need the CSRF token of the session, so that other
+//! sites can't make the browser of a user send them. Forms carry it in a `csrf` field and scripts
+//! in an `X-CSRF-Token` header.
+
+use crate::{
+    error::{error_response, HttpError},
+    signing::Signer,
+    AppState,
+};
+use actix_web::{
+    cookie::{time, Cookie, SameSite},
+    dev::ServiceRequest,
+    http::{header::LOCATION, Method},
+    web::{self, Bytes},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const COOKIE_NAME: &str = "session";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+struct SessionEntry {
+    user: String,
+    started: Instant,
+    last_seen: Instant,
+}
+
+/// Sessions started since startup
+pub struct Sessions {
+    signer: Signer,
+    idle_timeout: Duration,
+    max_age: Duration,
+    sessions: Mutex<HashMap<String, SessionEntry>>,
+}
+
+/// Session that a request was sent in, found in the request extensions
+#[derive(Clone)]
+pub struct Session {
+    id: String,
+    /// Token that requests changing anything have to carry
+    pub csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LoginTemplateContext<'a> {
+    next: &'a str,
+    failed: bool,
+}
+
+impl Sessions {
+    pub fn new(idle_timeout: Duration, max_age: Duration) -> Self {
+        Sessions {
+            signer: Signer::new(),
+            idle_timeout,
+            max_age,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_over(&self, entry: &SessionEntry, now: Instant) -> bool {
+        now.duration_since(entry.last_seen) >= self.idle_timeout
+            || now.duration_since(entry.started) >= self.max_age
+    }
+
+    /// Starts a session for `user`, returning the value of its cookie
+    fn start(&self, user: String) -> String {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, entry| !self.is_over(entry, now));
+        sessions.insert(
+            id.clone(),
+            SessionEntry {
+                user,
+                started: now,
+                last_seen: now,
+            },
+        );
+        format!("{}.{}", id, self.signer.sign("session", &id))
+    }
+
+    /// User and session that a cookie is for, if the session is still going. This counts as
+    /// activity in the session.
+    fn resume(&self, cookie_value: &str) -> Option<(String, Session)> {
+        let (id, signature) = cookie_value.split_once('.')?;
+        if !self.signer.verify("session", id, signature) {
+            return None;
+        }
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get_mut(id)?;
+        if self.is_over(entry, now) {
+            sessions.remove(id);
+            return None;
+        }
+        entry.last_seen = now;
+        let session = Session {
+            id: id.to_owned(),
+            csrf_token: self.signer.sign("csrf", id),
+        };
+        Some((entry.user.clone(), session))
+    }
+
+    fn end(&self, session: &Session) {
+        self.sessions.lock().unwrap().remove(&session.id);
+    }
+}
+
+/// The session that `req` was sent in, if its sender logged in with the login form
+pub fn current(req: &HttpRequest) -> Option<Session> {
+    req.extensions().get::<Session>().cloned()
+}
+
+/// Lets requests in with the cookie of a session that is still going. Returns whether there was
+/// one.
+pub fn attach(data: &AppState<'_>, req: &ServiceRequest) -> bool {
+    // The cookies are kept in the extensions, so they have to be let go of before adding to them
+    let Some(cookie_value) = req
+        .cookie(COOKIE_NAME)
+        .map(|cookie| cookie.value().to_owned())
+    else {
+        return false;
+    };
+    let Some((user, session)) = data.sessions.resume(&cookie_value) else {
+        return false;
+    };
+    req.extensions_mut().insert(crate::auth::User(user));
+    req.extensions_mut().insert(session);
+    true
+}
+
+/// Checks the CSRF token of requests sent in a session, taken from the header or, for forms, the
+/// `field` they have for it. Requests outside of sessions don't need one.
+pub fn check_csrf(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    field: Option<&str>,
+) -> Result<(), HttpError> {
+    let Some(session) = current(req) else {
+        return Ok(());
+    };
+    let header = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let valid = [header, field]
+        .into_iter()
+        .flatten()
+        .any(|token| data.sessions.signer.verify("csrf", &session.id, token));
+    if valid {
+        Ok(())
+    } else {
+        Err(HttpError::Forbidden)
+    }
+}
+
+/// Requests in a session that may change anything need a CSRF token. Forms and uploads have it
+/// checked where they are handled, since it can be in their body.
+pub fn check_request(data: &AppState<'_>, req: &HttpRequest) -> Result<(), HttpError> {
+    match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::POST => Ok(()),
+        _ => check_csrf(data, req, None),
+    }
+}
+
+/// Where to go after logging in. Only paths on this server are accepted, so that the login form
+/// can't be used to send users elsewhere.
+fn next_path(next: Option<&str>) -> &str {
+    match next {
+        Some(next) if next.starts_with('/') && !next.starts_with("//") && !next.contains('\\') => {
+            next
+        }
+        _ => "/",
+    }
+}
+
+fn login_page(data: &AppState<'_>, next: &str, failed: bool) -> HttpResponse {
+    let body = data
+        .hbs
+        .render_template(
+            crate::LOGIN_TEMPLATE,
+            &LoginTemplateContext { next, failed },
+        )
+        .expect("Handlebars failed at rendering");
+    let mut builder = if failed {
+        HttpResponse::Forbidden()
+    } else {
+        HttpResponse::Ok()
+    };
+    builder.body(body)
+}
+
+/// Shows the login form. `next` is the page to go to after logging in.
+pub async fn login_form(
+    data: web::Data<AppState<'_>>,
+    query: web::Query<LoginQuery>,
+) -> HttpResponse {
+    if data.auth.is_none() {
+        return error_response(&data.hbs, HttpError::NotFound);
+    }
+    login_page(&data, next_path(query.next.as_deref()), false)
+}
+
+/// Handles the login form, starting a session if the user name and password are right
+pub async fn login(
+    data: web::Data<AppState<'static>>,
+    req: HttpRequest,
+    body: Bytes,
+) -> HttpResponse {
+    if data.auth.is_none() {
+        return error_response(&data.hbs, HttpError::NotFound);
+    }
+    let mut fields: HashMap<String, String> = form_urlencoded::parse(&body).into_owned().collect();
+    let user = fields.remove("user").unwrap_or_default();
+    let password = fields.remove("password").unwrap_or_default();
+    let next = next_path(fields.get("next").map(String::as_str)).to_owned();
+
+    let verifier = data.clone();
+    let checked_user = user.clone();
+    let valid = web::block(move || {
+        verifier
+            .auth
+            .as_ref()
+            .is_some_and(|auth| auth.verify(&checked_user, &password))
+    })
+    .await
+    .unwrap_or(false);
+    if !valid {
+        let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+        eprintln!(
+            "Failed login as {} from {}",
+            user,
+            peer.as_deref().unwrap_or("unknown address")
+        );
+        return login_page(&data, &next, true);
+    }
+
+    let cookie = Cookie::build(COOKIE_NAME, data.sessions.start(user))
+        .path("/")
+        .http_only(true)
+        .secure(req.connection_info().scheme() == "https")
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(
+            data.sessions.max_age.as_secs() as i64
+        ))
+        .finish();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header((LOCATION, next))
+        .finish()
+}
+
+/// Ends the session of the sender, who is sent to the login form
+pub async fn logout(data: web::Data<AppState<'_>>, req: HttpRequest, body: Bytes) -> HttpResponse {
+    if let Some(session) = current(&req) {
+        let csrf_token = form_urlencoded::parse(&body)
+            .find(|(name, _)| name == "csrf")
+            .map(|(_, token)| token);
+        if let Err(http_error) = check_csrf(&data, &req, csrf_token.as_deref()) {
+            return error_response(&data.hbs, http_error);
+        }
+        data.sessions.end(&session);
+    }
+    let mut cookie = Cookie::build(COOKIE_NAME, "").path("/").finish();
+    cookie.make_removal();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header((LOCATION, "/.login"))
+        .finish()
+}
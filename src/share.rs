@@ -0,0 +1,294 @@
+//! Links to a file or directory for people without an account. Everything about a link is in
+//! its signed token, so links stop working when the server restarts.
+//! Opening a link sets a cookie that lets its visitor read what was shared, as the user that
+//! created the link, until it expires.
+
+use crate::{
+    error::{error_response, HttpError},
+    signing::Signer,
+    AppState,
+};
+use actix_web::{
+    cookie::{time, Cookie, SameSite},
+    dev::ServiceRequest,
+    http::{header::LOCATION, Method},
+    web::{self, Bytes},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Longest time a link can be valid for
+pub const MAX_LIFETIME: u64 = 30 * 24 * 60 * 60;
+
+const COOKIE_PREFIX: &str = "share-";
+
+/// What a link gives access to, as stored in its token
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Share {
+    /// Random ID that downloads are counted under
+    id: String,
+    /// Path from the root of the shared file or directory
+    path: String,
+    /// Unix time in seconds at which the link stops working
+    expires: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_downloads: Option<u32>,
+    /// Signature of the password, if the link has one, so that it can't be guessed from the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    /// Who created the link. Their access rules apply to its visitors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+/// Signing key and download counts of the links made since startup
+pub struct ShareState {
+    signer: Signer,
+    downloads: Mutex<HashMap<String, u32>>,
+}
+
+#[derive(Serialize)]
+struct PasswordTemplateContext {
+    wrong_password: bool,
+}
+
+impl ShareState {
+    pub fn new() -> Self {
+        ShareState {
+            signer: Signer::new(),
+            downloads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Makes a link token for `path`. `password`, if given, has to be entered to open the link.
+    pub fn mint(
+        &self,
+        path: String,
+        lifetime: u64,
+        max_downloads: Option<u32>,
+        password: Option<&str>,
+        user: Option<String>,
+    ) -> (String, u64) {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let share = Share {
+            password: password.map(|password| {
+                self.signer
+                    .sign("password", &format!("{}:{}", id, password))
+            }),
+            id,
+            path,
+            expires: now() + lifetime.min(MAX_LIFETIME),
+            max_downloads,
+            user,
+        };
+        let payload =
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&share).expect("Shares serialize to JSON"));
+        let signature = self.signer.sign("link", &payload);
+        (format!("{}.{}", payload, signature), share.expires)
+    }
+
+    /// The share in a token, if it was made here and hasn't expired. It may be used up, which is
+    /// checked when downloading.
+    fn open(&self, token: &str) -> Result<Share, HttpError> {
+        let (payload, signature) = token.split_once('.').ok_or(HttpError::NotFound)?;
+        if !self.signer.verify("link", payload, signature) {
+            return Err(HttpError::NotFound);
+        }
+        let share: Share = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(HttpError::NotFound)?;
+        if share.expires <= now() {
+            return Err(HttpError::Gone);
+        }
+        Ok(share)
+    }
+
+    fn used_up(&self, share: &Share) -> bool {
+        share.max_downloads.is_some_and(|max_downloads| {
+            self.downloads
+                .lock()
+                .unwrap()
+                .get(&share.id)
+                .copied()
+                .unwrap_or(0)
+                >= max_downloads
+        })
+    }
+
+    /// Counts a download made through a link, refusing it if the link was used up
+    pub fn count_download(&self, share: &Share) -> Result<(), HttpError> {
+        let Some(max_downloads) = share.max_downloads else {
+            return Ok(());
+        };
+        let mut downloads = self.downloads.lock().unwrap();
+        let count = downloads.entry(share.id.clone()).or_insert(0);
+        if *count >= max_downloads {
+            return Err(HttpError::Gone);
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    fn check_password(&self, share: &Share, password: &str) -> bool {
+        share.password.as_ref().is_some_and(|signature| {
+            self.signer
+                .verify("password", &format!("{}:{}", share.id, password), signature)
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The share that `req` was let in with, if it came from a link visitor
+pub fn current(req: &HttpRequest) -> Option<Share> {
+    req.extensions().get::<Share>().cloned()
+}
+
+/// Lets link visitors in if a cookie of theirs is for a link that covers the requested path.
+/// Returns whether one was found.
+pub fn attach(data: &AppState<'_>, req: &ServiceRequest) -> bool {
+    let path = percent_encoding::percent_decode_str(req.path()).decode_utf8_lossy();
+    // The cookies are kept in the extensions, so they have to be let go of before adding to them
+    let Ok(cookies) = req.cookies().map(|cookies| cookies.clone()) else {
+        return false;
+    };
+    let share = cookies.iter().find_map(|cookie| {
+        cookie.name().strip_prefix(COOKIE_PREFIX)?;
+        let (token, unlocked) = match cookie.value().split_once('~') {
+            Some((token, unlocked)) => (token, Some(unlocked)),
+            None => (cookie.value(), None),
+        };
+        let share = data.shares.open(token).ok()?;
+        if share.password.is_some()
+            && !unlocked
+                .is_some_and(|unlocked| data.shares.signer.verify("unlocked", &share.id, unlocked))
+        {
+            return None;
+        }
+        covers(&share, &path).then_some(share)
+    });
+    let Some(share) = share else {
+        return false;
+    };
+    if let Some(user) = &share.user {
+        req.extensions_mut().insert(crate::auth::User(user.clone()));
+    }
+    req.extensions_mut().insert(share);
+    true
+}
+
+/// Whether a link to `share` lets its visitors request `path`. Pages need the static files too.
+fn covers(share: &Share, path: &str) -> bool {
+    let shared = share.path.trim_end_matches('/');
+    path.starts_with("/static/")
+        || path == share.path
+        || (path.starts_with(shared)
+            && path[shared.len()..].starts_with('/')
+            && !path.starts_with("/.tus"))
+}
+
+/// Link visitors can only look at things and download them
+pub fn check_request(req: &HttpRequest) -> Result<(), HttpError> {
+    if req.extensions().get::<Share>().is_none() {
+        return Ok(());
+    }
+    match *req.method() {
+        Method::GET | Method::HEAD => Ok(()),
+        // Form actions other than downloads are refused in `actions`
+        Method::POST if crate::is_form_submission(req) => Ok(()),
+        _ => Err(HttpError::Forbidden),
+    }
+}
+
+/// Handles visits to a link, asking for the password if it has one and otherwise sending the
+/// visitor on to what was shared
+pub async fn open(
+    data: web::Data<AppState<'_>>,
+    req: HttpRequest,
+    token: web::Path<String>,
+) -> HttpResponse {
+    match data.shares.open(&token) {
+        Ok(share) if data.shares.used_up(&share) => error_response(&data.hbs, HttpError::Gone),
+        Ok(share) if share.password.is_some() => password_page(&data, false),
+        Ok(share) => enter(&data, &req, &share, token.into_inner()),
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+/// Handles the password form of a link
+pub async fn unlock(
+    data: web::Data<AppState<'_>>,
+    req: HttpRequest,
+    token: web::Path<String>,
+    body: Bytes,
+) -> HttpResponse {
+    let share = match data.shares.open(&token) {
+        Ok(share) if data.shares.used_up(&share) => {
+            return error_response(&data.hbs, HttpError::Gone)
+        }
+        Ok(share) => share,
+        Err(http_error) => return error_response(&data.hbs, http_error),
+    };
+    let password = form_urlencoded::parse(&body)
+        .find(|(name, _)| name == "password")
+        .map(|(_, password)| password)
+        .unwrap_or_default();
+    if !data.shares.check_password(&share, &password) {
+        return password_page(&data, true);
+    }
+    let unlocked = data.shares.signer.sign("unlocked", &share.id);
+    enter(&data, &req, &share, format!("{}~{}", token, unlocked))
+}
+
+fn password_page(data: &AppState<'_>, wrong_password: bool) -> HttpResponse {
+    let body = data
+        .hbs
+        .render_template(
+            crate::SHARE_TEMPLATE,
+            &PasswordTemplateContext { wrong_password },
+        )
+        .expect("Handlebars failed at rendering");
+    let mut builder = if wrong_password {
+        HttpResponse::Forbidden()
+    } else {
+        HttpResponse::Ok()
+    };
+    builder.body(body)
+}
+
+/// Sets the cookie for a link and redirects to what it is for
+fn enter(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    share: &Share,
+    cookie_value: String,
+) -> HttpResponse {
+    let is_dir = data
+        .local_path(share.path.trim_start_matches('/'))
+        .is_some_and(|local_path| local_path.is_dir());
+    let cookie = Cookie::build(format!("{}{}", COOKIE_PREFIX, share.id), cookie_value)
+        .path("/")
+        .http_only(true)
+        .secure(req.connection_info().scheme() == "https")
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(
+            share.expires.saturating_sub(now()) as i64
+        ))
+        .finish();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header((LOCATION, crate::webdav::href(&share.path, is_dir)))
+        .finish()
+}
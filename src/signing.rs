@@ -0,0 +1,41 @@
+//! Signatures for values given to clients that have to come back unchanged, like share link
+//! tokens and session cookies. The key is made at startup, so signatures stop being valid when
+//! the server restarts.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub struct Signer {
+    key: [u8; 32],
+}
+
+impl Signer {
+    pub fn new() -> Self {
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+        key[16..].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+        Signer { key }
+    }
+
+    /// HMAC-SHA256 of `value`. `purpose` keeps a signature made for one thing from being valid
+    /// for another.
+    fn mac(&self, purpose: &str, value: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key size");
+        mac.update(purpose.as_bytes());
+        mac.update(&[0]);
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, purpose: &str, value: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(purpose, value).finalize().into_bytes())
+    }
+
+    /// Checks a signature in constant time
+    pub fn verify(&self, purpose: &str, value: &str, signature: &str) -> bool {
+        URL_SAFE_NO_PAD
+            .decode(signature)
+            .is_ok_and(|signature| self.mac(purpose, value).verify_slice(&signature).is_ok())
+    }
+}
@@ -5,15 +5,37 @@ use aho_corasick::{AhoCorasick, PatternID};
 
 const CARET: PatternID = PatternID::from_u32_unchecked(0);
 const CLOUD: PatternID = PatternID::from_u32_unchecked(1);
-const FAVICON: PatternID = PatternID::from_u32_unchecked(2);
-const FILE: PatternID = PatternID::from_u32_unchecked(3);
-const FOLDER: PatternID = PatternID::from_u32_unchecked(4);
-const HOME: PatternID = PatternID::from_u32_unchecked(5);
-const LAYOUT: PatternID = PatternID::from_u32_unchecked(6);
+const DIRECTORY: PatternID = PatternID::from_u32_unchecked(2);
+const DOWNLOAD: PatternID = PatternID::from_u32_unchecked(3);
+const FAVICON: PatternID = PatternID::from_u32_unchecked(4);
+const FILE: PatternID = PatternID::from_u32_unchecked(5);
+const FOLDER: PatternID = PatternID::from_u32_unchecked(6);
+const HOME: PatternID = PatternID::from_u32_unchecked(7);
+const LAYOUT: PatternID = PatternID::from_u32_unchecked(8);
+const LOGOUT: PatternID = PatternID::from_u32_unchecked(9);
+const MKDIR: PatternID = PatternID::from_u32_unchecked(10);
+const RENAME: PatternID = PatternID::from_u32_unchecked(11);
+const SHARE: PatternID = PatternID::from_u32_unchecked(12);
+const TRASH: PatternID = PatternID::from_u32_unchecked(13);
+const UPLOAD: PatternID = PatternID::from_u32_unchecked(14);
 
 pub fn build_aho_corasick() -> AhoCorasick {
     let patterns = &[
-        "caret", "cloud", "favicon", "file", "folder", "home", "layout",
+        "caret",
+        "cloud",
+        "directory",
+        "download",
+        "favicon",
+        "file",
+        "folder",
+        "home",
+        "layout",
+        "logout",
+        "mkdir",
+        "rename",
+        "share",
+        "trash",
+        "upload",
     ];
     AhoCorasick::new(patterns).unwrap()
 }
@@ -37,11 +59,19 @@ pub async fn serve_static_file(
         let (bytes, mime) = match mat.pattern() {
             CARET => include_static_file!("caret", "svg"),
             CLOUD => include_static_file!("cloud", "svg"),
+            DIRECTORY => include_static_file!("directory", "svg"),
+            DOWNLOAD => include_static_file!("download", "svg"),
             FAVICON => include_static_file!("favicon", "png"),
             FILE => include_static_file!("file", "svg"),
             FOLDER => include_static_file!("folder", "svg"),
             HOME => include_static_file!("home", "svg"),
             LAYOUT => include_static_file!("layout", "css"),
+            LOGOUT => include_static_file!("logout", "svg"),
+            MKDIR => include_static_file!("mkdir", "svg"),
+            RENAME => include_static_file!("rename", "svg"),
+            SHARE => include_static_file!("share", "svg"),
+            TRASH => include_static_file!("trash", "svg"),
+            UPLOAD => include_static_file!("upload", "js"),
             _ => unreachable!(),
         };
         return HttpResponse::Ok()
@@ -0,0 +1,549 @@
+use crate::{
+    error::{error_response, HttpError},
+    extract::ArchiveKind,
+    webdav::LockTokens,
+    AppState,
+};
+use actix_web::{
+    http::header::{self, HeaderValue, LOCATION},
+    web::{self, Payload},
+    HttpRequest, HttpResponse,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tokio::io::AsyncWriteExt;
+
+const TUS_VERSION: &str = "1.0.0";
+
+const TUS_EXTENSIONS: &str = "creation,termination";
+
+/// Unfinished uploads that haven't been touched for this long are removed on startup
+const STALE_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Uploads made with the tus protocol, which are kept in their own directory until complete
+pub struct TusState {
+    dir: PathBuf,
+    /// Uploads that are currently receiving data
+    active: Mutex<HashSet<String>>,
+}
+
+/// Everything known about an upload, stored next to its data as JSON
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    length: u64,
+    /// Path from the root of the directory that the file goes in
+    directory: String,
+    /// Directories under `directory` to create for the file, for uploads of whole folders
+    #[serde(default)]
+    subdirectories: Vec<String>,
+    file_name: String,
+    /// Modification time to give the file, from the `lastModified` metadata
+    #[serde(default)]
+    modified: Option<SystemTime>,
+    /// `Upload-Metadata` header as sent by the client
+    metadata: String,
+    /// Who created the upload, if users have to log in
+    #[serde(default)]
+    user: Option<String>,
+    /// Whether the file is an archive to unpack into the directory instead of storing it
+    #[serde(default)]
+    extract: bool,
+    /// Set once the upload is complete and moved into place
+    complete: bool,
+}
+
+impl TusState {
+    /// Uses `dir` for unfinished uploads, creating it if needed and removing stale uploads.
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let now = SystemTime::now();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let is_stale = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| {
+                    now.duration_since(modified)
+                        .is_ok_and(|age| age > STALE_AFTER)
+                });
+            if is_stale {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+        Ok(TusState {
+            dir,
+            active: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Paths of the JSON description and the data of an upload, if `id` is a valid ID
+    fn paths(&self, id: &str) -> Option<(PathBuf, PathBuf)> {
+        let id = canonical_id(id).ok()?;
+        Some((
+            self.dir.join(format!("{}.json", id)),
+            self.dir.join(format!("{}.part", id)),
+        ))
+    }
+
+    /// Loads an upload of `user`. Uploads of other users can't be found.
+    fn load(&self, id: &str, user: Option<&str>) -> Result<(Upload, PathBuf, PathBuf), HttpError> {
+        let (info_path, data_path) = self.paths(id).ok_or(HttpError::NotFound)?;
+        let json = std::fs::read(&info_path).map_err(|_| HttpError::NotFound)?;
+        let upload: Upload =
+            serde_json::from_slice(&json).map_err(|_| HttpError::InternalServerError)?;
+        if upload.user.as_deref() != user {
+            return Err(HttpError::NotFound);
+        }
+        Ok((upload, info_path, data_path))
+    }
+}
+
+/// IDs in the form they are stored under, since the same ID can be written in several ways
+fn canonical_id(id: &str) -> Result<String, HttpError> {
+    uuid::Uuid::try_parse(id)
+        .map(|id| id.simple().to_string())
+        .map_err(|_| HttpError::NotFound)
+}
+
+/// Handles `OPTIONS` requests, telling clients what the server supports
+pub async fn options(data: web::Data<AppState<'_>>) -> HttpResponse {
+    let mut builder = HttpResponse::NoContent();
+    builder
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS));
+    if let Some(max_size) = data.max_upload_size {
+        builder.insert_header(("Tus-Max-Size", max_size));
+    }
+    builder.finish()
+}
+
+/// Creates an upload. The `filename` metadata names the file and `directory` is the path from
+/// the root of the directory it goes in. A `relativePath` under that directory can be given
+/// instead of the name to upload into subdirectories, which are created when the upload is done.
+/// `lastModified` sets the modification time of the file, in milliseconds since the Unix epoch.
+/// With `extract` set to `true`, a `.zip` or `.tar.gz` file is unpacked into the directory once
+/// complete, each of its files placed like an upload of its own, instead of being stored.
+pub async fn create(data: web::Data<AppState<'_>>, req: HttpRequest) -> HttpResponse {
+    tus_response(&data, create_upload(&data, &req).await, |id| {
+        let mut builder = HttpResponse::Created();
+        builder.insert_header((LOCATION, format!("/.tus/{}", id)));
+        builder
+    })
+}
+
+async fn create_upload(data: &AppState<'_>, req: &HttpRequest) -> Result<String, HttpError> {
+    check_version(req)?;
+    check_mode(data)?;
+    crate::session::check_csrf(data, req, None)?;
+    let length = header_u64(req, "Upload-Length")?;
+    if data
+        .max_upload_size
+        .is_some_and(|max_size| length > max_size)
+    {
+        return Err(HttpError::PayloadTooLarge);
+    }
+    let metadata = req
+        .headers()
+        .get("Upload-Metadata")
+        .map(|metadata| metadata.to_str().map_err(|_| HttpError::BadRequest))
+        .transpose()?
+        .unwrap_or("");
+
+    let file_name = metadata_value(metadata, "relativePath")
+        .or_else(|| metadata_value(metadata, "filename"))
+        .or_else(|| metadata_value(metadata, "name"))
+        .ok_or(HttpError::BadRequest)?;
+    let (subdirectories, file_name) = crate::upload::relative_path(data, &file_name)?;
+    let extract = metadata_value(metadata, "extract")
+        .is_some_and(|value| matches!(value.trim(), "true" | "1" | "on"));
+    // Archives aren't stored themselves, so only what they hold is checked when it is placed
+    if extract {
+        ArchiveKind::from_name(&file_name).ok_or(HttpError::UnsupportedMediaType)?;
+    } else {
+        data.upload_types.check_name(&file_name)?;
+    }
+    let modified = metadata_value(metadata, "lastModified")
+        .map(|value| crate::upload::parse_millis(&value))
+        .transpose()?;
+    let directory = metadata_value(metadata, "directory").unwrap_or_default();
+    let directory = directory.trim_matches('/').to_owned();
+    let dir = data.local_path(&directory).ok_or(HttpError::BadRequest)?;
+    if !dir.is_dir() {
+        return Err(HttpError::NotFound);
+    }
+    // Fail early instead of after the whole file was sent
+    let target_dir = subdirectories.iter().fold(dir, |dir, name| dir.join(name));
+    let user = crate::auth::user(req);
+    if !extract {
+        crate::acl::check(
+            data,
+            user.as_deref(),
+            &target_dir.join(&file_name),
+            crate::acl::Permission::Upload,
+        )?;
+        if target_dir.is_dir() {
+            crate::upload::upload_target(data, &target_dir, &file_name)?;
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let (info_path, data_path) = data.tus.paths(&id).ok_or(HttpError::InternalServerError)?;
+    let upload = Upload {
+        length,
+        directory,
+        subdirectories,
+        file_name,
+        modified,
+        metadata: metadata.to_owned(),
+        user,
+        extract,
+        complete: false,
+    };
+    tokio::fs::File::create(&data_path)
+        .await
+        .map_err(io_error)?;
+    save(&info_path, &upload).await?;
+
+    if length == 0 {
+        finish(data, &info_path, &data_path, upload, &LockTokens::of(req)).await?;
+    }
+    Ok(id)
+}
+
+/// Handles `HEAD` requests, telling the client how much of the upload has arrived
+pub async fn head(
+    data: web::Data<AppState<'_>>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let result = check_version(&req).and_then(|()| {
+        check_mode(&data)?;
+        let user = crate::auth::user(&req);
+        let (upload, _, data_path) = data.tus.load(&id, user.as_deref())?;
+        let offset = offset(&upload, &data_path)?;
+        Ok((upload, offset))
+    });
+    tus_response(&data, result, |(upload, offset)| {
+        let mut builder = HttpResponse::Ok();
+        builder
+            .insert_header(("Upload-Offset", offset))
+            .insert_header(("Upload-Length", upload.length))
+            .insert_header((header::CACHE_CONTROL, "no-store"));
+        if !upload.metadata.is_empty() {
+            builder.insert_header(("Upload-Metadata", upload.metadata));
+        }
+        builder
+    })
+}
+
+/// Handles `PATCH` requests, appending the body to the upload at the offset the client gives.
+/// Whatever arrived before a disconnect is kept so that the client can resume from there.
+pub async fn patch(
+    data: web::Data<AppState<'_>>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    payload: Payload,
+) -> HttpResponse {
+    let result = append(&data, &req, &id, payload).await;
+    tus_response(&data, result, |offset| {
+        let mut builder = HttpResponse::NoContent();
+        builder.insert_header(("Upload-Offset", offset));
+        builder
+    })
+}
+
+async fn append(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    id: &str,
+    payload: Payload,
+) -> Result<u64, HttpError> {
+    check_version(req)?;
+    check_mode(data)?;
+    crate::session::check_csrf(data, req, None)?;
+    let is_offset_stream = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/offset+octet-stream");
+    if !is_offset_stream {
+        return Err(HttpError::UnsupportedMediaType);
+    }
+    let client_offset = header_u64(req, "Upload-Offset")?;
+
+    // Only one request at a time can write to an upload, so its offset is only known for sure
+    // once this one is it
+    let id = canonical_id(id)?;
+    let _active = Active::claim(&data.tus, &id).ok_or(HttpError::Conflict)?;
+    let user = crate::auth::user(req);
+    let (upload, info_path, data_path) = data.tus.load(&id, user.as_deref())?;
+    let mut offset = offset(&upload, &data_path)?;
+    if client_offset != offset {
+        return Err(HttpError::Conflict);
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&data_path)
+        .await
+        .map_err(io_error)?;
+    let mut payload = payload.into_inner();
+    let mut too_long = false;
+    while let Some(chunk) = payload.next().await {
+        // The client disconnected, which is what resuming is for
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => break,
+        };
+        if offset + chunk.len() as u64 > upload.length {
+            too_long = true;
+            break;
+        }
+        file.write_all(&chunk).await.map_err(io_error)?;
+        offset += chunk.len() as u64;
+    }
+    file.flush().await.map_err(io_error)?;
+    drop(file);
+    if too_long {
+        return Err(HttpError::BadRequest);
+    }
+
+    if offset == upload.length {
+        finish(data, &info_path, &data_path, upload, &LockTokens::of(req)).await?;
+    }
+    Ok(offset)
+}
+
+/// Handles `DELETE` requests, abandoning an upload
+pub async fn terminate(
+    data: web::Data<AppState<'_>>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let result = async {
+        check_version(&req)?;
+        check_mode(&data)?;
+        crate::session::check_csrf(&data, &req, None)?;
+        let id = canonical_id(&id)?;
+        let user = crate::auth::user(&req);
+        let (_, info_path, data_path) = data.tus.load(&id, user.as_deref())?;
+        let _active = Active::claim(&data.tus, &id).ok_or(HttpError::Conflict)?;
+        let _ = tokio::fs::remove_file(&data_path).await;
+        tokio::fs::remove_file(&info_path).await.map_err(io_error)
+    }
+    .await;
+    tus_response(&data, result, |()| HttpResponse::NoContent())
+}
+
+/// Moves a complete upload into place, keeping its description so that clients checking on it
+/// later see that it is done. Locks on where it goes need their tokens in `tokens`, sent with the
+/// request completing the upload.
+async fn finish(
+    data: &AppState<'_>,
+    info_path: &Path,
+    data_path: &Path,
+    mut upload: Upload,
+    tokens: &LockTokens,
+) -> Result<(), HttpError> {
+    if upload.extract {
+        return finish_extract(data, info_path, data_path, upload, tokens).await;
+    }
+    if let Err(http_error) = data
+        .upload_types
+        .check_contents(&upload.file_name, data_path)
+    {
+        // Sending the file again won't change its type
+        let _ = tokio::fs::remove_file(data_path).await;
+        let _ = tokio::fs::remove_file(info_path).await;
+        return Err(http_error);
+    }
+    let dir = data
+        .local_path(&upload.directory)
+        .ok_or(HttpError::Conflict)?;
+    let dir = crate::upload::create_subdirectories(
+        data,
+        &dir,
+        &upload.subdirectories,
+        upload.user.as_deref(),
+        tokens,
+    )
+    .await?;
+    let target = crate::upload::upload_target(data, &dir, &upload.file_name)?;
+    let (target, _) =
+        crate::upload::place(data, data_path, &dir, &upload.file_name, target, tokens).await?;
+    // The file is in place already, so this is not worth failing the upload over
+    if let Some(modified) = upload.modified {
+        if let Err(e) = crate::upload::set_modified(&target, modified) {
+            eprintln!("Upload error: {}", e);
+        }
+    }
+    upload.complete = true;
+    save(info_path, &upload).await?;
+    crate::upload::run_hook(
+        data,
+        &target,
+        upload.length,
+        None,
+        "tus",
+        upload.user.as_deref(),
+    );
+    Ok(())
+}
+
+/// Unpacks a complete archive into its directory. Entries that can't be placed are left out, as
+/// tus has no way of telling the client about them.
+async fn finish_extract(
+    data: &AppState<'_>,
+    info_path: &Path,
+    data_path: &Path,
+    mut upload: Upload,
+    tokens: &LockTokens,
+) -> Result<(), HttpError> {
+    let kind = ArchiveKind::from_name(&upload.file_name).ok_or(HttpError::UnsupportedMediaType)?;
+    let dir = data
+        .local_path(&upload.directory)
+        .ok_or(HttpError::Conflict)?;
+    let dir = crate::upload::create_subdirectories(
+        data,
+        &dir,
+        &upload.subdirectories,
+        upload.user.as_deref(),
+        tokens,
+    )
+    .await?;
+    let result = crate::upload::extract_into(
+        data,
+        kind,
+        data_path,
+        &dir,
+        "tus",
+        upload.user.as_deref(),
+        tokens,
+    )
+    .await;
+    // The archive itself is never stored, and sending it again won't unpack it differently
+    let _ = tokio::fs::remove_file(data_path).await;
+    if let Err(http_error) = result {
+        let _ = tokio::fs::remove_file(info_path).await;
+        return Err(http_error);
+    }
+    upload.complete = true;
+    save(info_path, &upload).await
+}
+
+fn offset(upload: &Upload, data_path: &Path) -> Result<u64, HttpError> {
+    if upload.complete {
+        return Ok(upload.length);
+    }
+    std::fs::metadata(data_path)
+        .map(|metadata| metadata.len())
+        .map_err(|_| HttpError::NotFound)
+}
+
+async fn save(info_path: &Path, upload: &Upload) -> Result<(), HttpError> {
+    let json = serde_json::to_vec(upload).map_err(|_| HttpError::InternalServerError)?;
+    tokio::fs::write(info_path, json).await.map_err(io_error)
+}
+
+/// Marks an upload as active for as long as it is alive. Takes IDs from `canonical_id`.
+struct Active<'a> {
+    tus: &'a TusState,
+    id: String,
+}
+
+impl<'a> Active<'a> {
+    fn claim(tus: &'a TusState, id: &str) -> Option<Self> {
+        tus.active
+            .lock()
+            .unwrap()
+            .insert(id.to_owned())
+            .then(|| Active {
+                tus,
+                id: id.to_owned(),
+            })
+    }
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.tus.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Read-only servers refuse uploads, including those started before
+fn check_mode(data: &AppState<'_>) -> Result<(), HttpError> {
+    if data.mode.can_upload() {
+        Ok(())
+    } else {
+        Err(HttpError::Forbidden)
+    }
+}
+
+fn check_version(req: &HttpRequest) -> Result<(), HttpError> {
+    match req.headers().get("Tus-Resumable") {
+        Some(version) if version == TUS_VERSION => Ok(()),
+        _ => Err(HttpError::PreconditionFailed),
+    }
+}
+
+fn header_u64(req: &HttpRequest, name: &str) -> Result<u64, HttpError> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or(HttpError::BadRequest)
+}
+
+/// Decoded value of `key` in an `Upload-Metadata` header, which holds comma separated keys each
+/// followed by a base64 encoded value
+fn metadata_value(metadata: &str, key: &str) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.split_whitespace();
+        if parts.next()? != key {
+            return None;
+        }
+        let value = STANDARD.decode(parts.next().unwrap_or("")).ok()?;
+        String::from_utf8(value).ok()
+    })
+}
+
+/// Response for the result of a tus request, which always carries `Tus-Resumable`
+fn tus_response<T>(
+    data: &AppState<'_>,
+    result: Result<T, HttpError>,
+    builder: impl FnOnce(T) -> actix_web::HttpResponseBuilder,
+) -> HttpResponse {
+    let mut http_response = match result {
+        Ok(value) => builder(value).finish(),
+        Err(http_error) => error_response(&data.hbs, http_error),
+    };
+    http_response.headers_mut().insert(
+        header::HeaderName::from_static("tus-resumable"),
+        HeaderValue::from_static(TUS_VERSION),
+    );
+    if http_response.status() == actix_web::http::StatusCode::PRECONDITION_FAILED {
+        http_response.headers_mut().insert(
+            header::HeaderName::from_static("tus-version"),
+            HeaderValue::from_static(TUS_VERSION),
+        );
+    }
+    http_response
+}
+
+fn io_error(e: io::Error) -> HttpError {
+    match e.kind() {
+        io::ErrorKind::NotFound => HttpError::NotFound,
+        io::ErrorKind::PermissionDenied => HttpError::Forbidden,
+        _ => {
+            eprintln!("Resumable upload error: {}", e);
+            HttpError::InternalServerError
+        }
+    }
+}
@@ -1,15 +1,110 @@
-use crate::AppState;
-use actix_multipart::Multipart;
+use crate::{
+    acl::Permission,
+    error::{error_json_response, error_response, HttpError},
+    extract::{self, ArchiveKind, Staged},
+    hook::UploadEvent,
+    rename::Placed,
+    webdav::LockTokens,
+    AppState,
+};
+use actix_multipart::{Field, Multipart};
 use actix_web::{
     http::header::LOCATION,
-    web::{self, Payload},
+    web::{self, Bytes, Payload},
     FromRequest, HttpRequest, HttpResponse,
 };
-use futures_util::TryStreamExt;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::io::AsyncWriteExt;
 
 pub type UploadResponseType = HttpResponse;
 
+/// Longest value accepted for the text fields describing an upload
+const MAX_TEXT_FIELD_LEN: usize = 1024;
+
+/// What to do when an upload has the same name as an existing file: replace the file, store the
+/// upload as "name (1).ext", "name (2).ext" and so on, or refuse the upload
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ConflictPolicy {
+    Overwrite,
+    Rename,
+    Reject,
+}
+
+/// Summary of a multipart upload for clients that asked for JSON
+#[derive(Serialize)]
+struct UploadSummary {
+    files: Vec<FieldSummary>,
+}
+
+#[derive(Serialize)]
+struct FieldSummary {
+    field: String,
+    file_name: Option<String>,
+    #[serde(flatten)]
+    outcome: FieldOutcome,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum FieldOutcome {
+    Stored {
+        path: String,
+        size: u64,
+        sha256: String,
+    },
+    /// An archive that was unpacked instead of stored
+    Extracted {
+        size: u64,
+        sha256: String,
+        entries: Vec<EntrySummary>,
+    },
+    Failed {
+        error: HttpError,
+    },
+}
+
+impl FieldOutcome {
+    /// The first error, including those of entries of archives
+    fn error(&self) -> Option<HttpError> {
+        match self {
+            FieldOutcome::Stored { .. } => None,
+            FieldOutcome::Extracted { entries, .. } => {
+                entries.iter().find_map(|entry| entry.outcome.error())
+            }
+            FieldOutcome::Failed { error } => Some(*error),
+        }
+    }
+}
+
+/// What happened to one entry of an unpacked archive
+#[derive(Serialize)]
+pub struct EntrySummary {
+    /// Path inside the archive
+    entry: String,
+    #[serde(flatten)]
+    outcome: FieldOutcome,
+}
+
+/// Handles multipart uploads into the directory at `web_path`. Browsers are redirected back to
+/// the listing. Clients asking for JSON get what happened to each field instead, where a field
+/// that failed doesn't keep the others from being stored.
+///
+/// A `sha256` text field holds the hex-encoded SHA-256 of the file field that follows it, which
+/// is refused if its contents turn out different, and a `lastModified` text field its
+/// modification time in milliseconds since the Unix epoch, as `File.lastModified` gives it.
+/// With an `extract` text field set to `true`, a `.zip` or `.tar.gz` file that follows is
+/// unpacked into the directory instead, and each of its files is placed like an upload of its
+/// own. Uploads in a login session need its CSRF token, in the header or a `csrf` text field
+/// before the files.
 pub async fn upload(
     data: web::Data<AppState<'_>>,
     req: HttpRequest,
@@ -18,54 +113,637 @@ pub async fn upload(
 ) -> HttpResponse {
     async fn inner(
         data: &web::Data<AppState<'_>>,
-        req: HttpRequest,
+        req: &HttpRequest,
         payload: Option<Payload>,
         web_path: String,
-    ) -> Result<HttpResponse, actix_web::Error> {
+        json: bool,
+    ) -> Result<Vec<FieldSummary>, HttpError> {
         // Path on the server
-        let local_path = data.serve_from.join(&web_path);
+        let local_path = data.local_path(&web_path).ok_or(HttpError::NotFound)?;
 
         let mut multipart_payload = match payload {
             Some(p) => {
                 let mut inner = p.into_inner();
-                Multipart::from_request(&req, &mut inner).await?
-            }
-            None => {
-                return Err(actix_web::error::ErrorInternalServerError(
-                    "Missing payload on POST",
-                ))
+                Multipart::from_request(req, &mut inner)
+                    .await
+                    .map_err(|_| HttpError::BadRequest)?
             }
+            None => return Err(HttpError::BadRequest),
         };
 
-        while let Some(mut field) = multipart_payload.try_next().await? {
-            // A multipart/form-data stream has to contain `content_disposition`
-            let content_disposition = field.content_disposition();
+        let user = crate::auth::user(req);
+        let tokens = LockTokens::of(req);
+        let mut has_csrf_token = crate::session::check_csrf(data, req, None).is_ok();
+        let mut summaries = Vec::new();
+        let mut details = FileDetails::default();
+        while let Some(field) = multipart_payload
+            .try_next()
+            .await
+            .map_err(|_| HttpError::BadRequest)?
+        {
+            let field_name = field
+                .content_disposition()
+                .get_name()
+                .unwrap_or_default()
+                .to_owned();
+            let file_name = field
+                .content_disposition()
+                .get_filename()
+                .map(str::to_owned);
+            // Text fields describe the file that follows them
+            if file_name.is_none() {
+                match field_name.as_str() {
+                    "sha256" => {
+                        let value = read_text_field(field).await?;
+                        details.sha256 = Some(parse_hex_digest(&value)?);
+                        continue;
+                    }
+                    "lastModified" => {
+                        let value = read_text_field(field).await?;
+                        details.modified = Some(parse_millis(&value)?);
+                        continue;
+                    }
+                    "extract" => {
+                        let value = read_text_field(field).await?;
+                        details.extract = matches!(value.trim(), "true" | "1" | "on");
+                        continue;
+                    }
+                    "csrf" => {
+                        let value = read_text_field(field).await?;
+                        crate::session::check_csrf(data, req, Some(&value))?;
+                        has_csrf_token = true;
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+            if !has_csrf_token {
+                return Err(HttpError::Forbidden);
+            }
+
+            let details = std::mem::take(&mut details);
+            let stored = store_field(data, &local_path, field, &details, user.as_deref(), &tokens);
+            let outcome = match stored.await {
+                Ok(outcome) => outcome,
+                Err(http_error) => FieldOutcome::Failed { error: http_error },
+            };
+            if let (false, Some(http_error)) = (json, outcome.error()) {
+                return Err(http_error);
+            }
+            summaries.push(FieldSummary {
+                field: field_name,
+                file_name,
+                outcome,
+            });
+        }
+        Ok(summaries)
+    }
+
+    let json = crate::index::accepts_json(&req);
+    match inner(&data, &req, payload, web_path, json).await {
+        Ok(files) if json => {
+            let all_stored = files.iter().all(|file| file.outcome.error().is_none());
+            let mut builder = if all_stored {
+                HttpResponse::Created()
+            } else {
+                HttpResponse::MultiStatus()
+            };
+            builder.json(UploadSummary { files })
+        }
+        Ok(_) => {
+            let mut response = HttpResponse::SeeOther();
+            response.append_header((LOCATION, req.path()));
+            response.finish()
+        }
+        Err(http_error) if json => error_json_response(http_error),
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+/// Stores one field of a multipart upload in `dir`, or unpacks it if it is an archive that
+/// should be extracted
+async fn store_field(
+    data: &AppState<'_>,
+    dir: &Path,
+    field: Field,
+    details: &FileDetails,
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<FieldOutcome, HttpError> {
+    // A multipart/form-data stream has to contain `content_disposition`. The file name may be a
+    // path relative to the upload directory when a whole folder is uploaded.
+    let (subdirectories, file_name) = match field.content_disposition().get_filename() {
+        Some(file_name) => relative_path(data, file_name)?,
+        None => return Err(HttpError::BadRequest),
+    };
+    // Checked before creating any directories. Archives aren't stored themselves, so only what
+    // they hold is checked when it is placed.
+    let target_dir = subdirectories
+        .iter()
+        .fold(dir.to_owned(), |dir, name| dir.join(name));
+    if !details.extract {
+        crate::acl::check(data, user, &target_dir.join(&file_name), Permission::Upload)?;
+    }
+    let dir = create_subdirectories(data, dir, &subdirectories, user, tokens).await?;
+
+    if details.extract {
+        let kind = ArchiveKind::from_name(&file_name).ok_or(HttpError::UnsupportedMediaType)?;
+        // The archive itself is never stored
+        let temp_path = dir.join(format!(".{}.{}.part", file_name, uuid::Uuid::new_v4()));
+        let result = async {
+            let written = write_temp_file(data, &temp_path, field, details).await?;
+            let entries = extract_into(data, kind, &temp_path, &dir, "form", user, tokens).await?;
+            Ok(FieldOutcome::Extracted {
+                size: written.size,
+                sha256: written.sha256,
+                entries,
+            })
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return result;
+    }
+
+    let stored = store(data, &dir, &file_name, field, details, tokens).await?;
+    run_hook(
+        data,
+        &stored.path,
+        stored.size,
+        Some(&stored.sha256),
+        "form",
+        user,
+    );
+    Ok(FieldOutcome::Stored {
+        path: data.web_path(&stored.path),
+        size: stored.size,
+        sha256: stored.sha256,
+    })
+}
 
-            let path = match content_disposition.get_filename() {
-                Some(file_name) => local_path.join(file_name),
-                None => {
-                    return Err(actix_web::error::ErrorInternalServerError(
-                        "Unable to get file name of upload",
-                    ));
+/// Unpacks the archive at `archive` into `dir`. Entries are placed like uploads of their own, so
+/// ones that fail, such as symlinks or paths leading out of `dir`, don't stop the others.
+/// `source` is how the archive was uploaded, as told to the upload hook.
+pub async fn extract_into(
+    data: &AppState<'_>,
+    kind: ArchiveKind,
+    archive: &Path,
+    dir: &Path,
+    source: &'static str,
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<Vec<EntrySummary>, HttpError> {
+    let staging = dir.join(format!(".{}.extract", uuid::Uuid::new_v4()));
+    tokio::fs::create_dir(&staging).await.map_err(io_error)?;
+    let result = async {
+        let entries = extract::unpack(
+            kind,
+            archive.to_owned(),
+            staging.clone(),
+            data.max_upload_size,
+        )
+        .await?;
+        let mut summaries = Vec::new();
+        for entry in entries {
+            let outcome = match entry.staged {
+                Some(staged) => {
+                    match place_entry(data, dir, &entry.name, &staged, user, tokens).await {
+                        Ok(path) => {
+                            run_hook(data, &path, staged.size, Some(&staged.sha256), source, user);
+                            FieldOutcome::Stored {
+                                path: data.web_path(&path),
+                                size: staged.size,
+                                sha256: staged.sha256,
+                            }
+                        }
+                        Err(http_error) => FieldOutcome::Failed { error: http_error },
+                    }
                 }
+                // Not a regular file
+                None => FieldOutcome::Failed {
+                    error: HttpError::UnsupportedMediaType,
+                },
             };
+            summaries.push(EntrySummary {
+                entry: entry.name,
+                outcome,
+            });
+        }
+        Ok(summaries)
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&staging).await;
+    result
+}
+
+async fn place_entry(
+    data: &AppState<'_>,
+    dir: &Path,
+    name: &str,
+    staged: &Staged,
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<PathBuf, HttpError> {
+    let (subdirectories, file_name) = relative_path(data, name)?;
+    let target_dir = subdirectories
+        .iter()
+        .fold(dir.to_owned(), |dir, name| dir.join(name));
+    crate::acl::check(data, user, &target_dir.join(&file_name), Permission::Upload)?;
+    let dir = create_subdirectories(data, dir, &subdirectories, user, tokens).await?;
+    let target = upload_target(data, &dir, &file_name)?;
+    data.upload_types.check_contents(&file_name, &staged.path)?;
+    let (path, _) = place(data, &staged.path, &dir, &file_name, target, tokens).await?;
+    Ok(path)
+}
+
+async fn read_text_field(mut field: Field) -> Result<String, HttpError> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.try_next().await.map_err(|_| HttpError::BadRequest)? {
+        value.extend_from_slice(&chunk);
+        if value.len() > MAX_TEXT_FIELD_LEN {
+            return Err(HttpError::BadRequest);
+        }
+    }
+    String::from_utf8(value).map_err(|_| HttpError::BadRequest)
+}
+
+/// Checks a hex-encoded SHA-256, returning it in lowercase
+fn parse_hex_digest(value: &str) -> Result<String, HttpError> {
+    let value = value.trim();
+    if value.len() == 64 && value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        Ok(value.to_ascii_lowercase())
+    } else {
+        Err(HttpError::BadRequest)
+    }
+}
 
-            let mut f = tokio::fs::File::create(path).await?;
-            while let Some(chunk) = field.try_next().await? {
-                f.write_all(&chunk).await?;
+/// Parses a time in milliseconds since the Unix epoch
+pub fn parse_millis(value: &str) -> Result<SystemTime, HttpError> {
+    let millis = value
+        .trim()
+        .parse::<i64>()
+        .map_err(|_| HttpError::BadRequest)?;
+    from_epoch(Duration::from_millis(millis.unsigned_abs()), millis < 0)
+}
+
+/// The modification time a client gives in `X-File-Mtime`, in seconds since the Unix epoch
+pub fn modified_header(req: &HttpRequest) -> Result<Option<SystemTime>, HttpError> {
+    let header_value = match req.headers().get("X-File-Mtime") {
+        Some(header_value) => header_value.to_str().map_err(|_| HttpError::BadRequest)?,
+        None => return Ok(None),
+    };
+    let secs = header_value
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| HttpError::BadRequest)?;
+    let offset = Duration::try_from_secs_f64(secs.abs()).map_err(|_| HttpError::BadRequest)?;
+    from_epoch(offset, secs < 0.0).map(Some)
+}
+
+/// Files can be older than the epoch, hence `before`
+fn from_epoch(offset: Duration, before: bool) -> Result<SystemTime, HttpError> {
+    let time = if before {
+        UNIX_EPOCH.checked_sub(offset)
+    } else {
+        UNIX_EPOCH.checked_add(offset)
+    };
+    time.ok_or(HttpError::BadRequest)
+}
+
+/// The SHA-256 a client expects its upload to have, from `Content-Digest` or the older `Digest`
+/// header, hex-encoded. A digest without a SHA-256 can't be checked and is refused.
+pub fn expected_digest(req: &HttpRequest) -> Result<Option<String>, HttpError> {
+    let header_value = match ["Content-Digest", "Digest"]
+        .into_iter()
+        .find_map(|name| req.headers().get(name))
+    {
+        Some(header_value) => header_value.to_str().map_err(|_| HttpError::BadRequest)?,
+        None => return Ok(None),
+    };
+    // `sha-256=:base64:` for `Content-Digest` and `SHA-256=base64` for `Digest`
+    let encoded = header_value
+        .split(',')
+        .filter_map(|item| item.split_once('='))
+        .find(|(algorithm, _)| algorithm.trim().eq_ignore_ascii_case("sha-256"))
+        .map(|(_, value)| value.trim().trim_matches(':'))
+        .ok_or(HttpError::BadRequest)?;
+    let digest = STANDARD
+        .decode(encoded)
+        .map_err(|_| HttpError::BadRequest)?;
+    if digest.len() != 32 {
+        return Err(HttpError::BadRequest);
+    }
+    Ok(Some(
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    ))
+}
+
+/// Splits a path relative to the upload directory into the directories leading to the file and
+/// its name, sanitizing each of them. Anything that would leave the upload directory or create
+/// hidden entries is refused.
+pub fn relative_path(
+    data: &AppState<'_>,
+    relative_path: &str,
+) -> Result<(Vec<String>, String), HttpError> {
+    let mut segments = Vec::new();
+    for segment in relative_path.split(['/', '\\']) {
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        let sanitized = sanitize_filename::sanitize(segment.trim());
+        if sanitized.is_empty() || segment == ".." || data.visibility.is_hidden(&sanitized) {
+            return Err(HttpError::BadRequest);
+        }
+        segments.push(sanitized);
+    }
+    let file_name = segments.pop().ok_or(HttpError::BadRequest)?;
+    Ok((segments, file_name))
+}
+
+/// Creates the directories returned by `relative_path` under `dir` where missing, returning the
+/// innermost one. Symlinks are not followed so that the upload stays under `dir`.
+pub async fn create_subdirectories(
+    data: &AppState<'_>,
+    dir: &Path,
+    subdirectories: &[String],
+    user: Option<&str>,
+    tokens: &LockTokens,
+) -> Result<PathBuf, HttpError> {
+    let mut path = dir.to_owned();
+    for name in subdirectories {
+        match tokio::fs::symlink_metadata(path.join(name)).await {
+            Ok(metadata) if metadata.is_dir() => path.push(name),
+            Ok(_) => return Err(HttpError::Conflict),
+            Err(_) => path = crate::mkdir::create_dir(data, &path, name, user, tokens).await?,
+        }
+    }
+    Ok(path)
+}
+
+/// Where an upload called `name` in `dir` ends up under the conflict policy, and whether it
+/// replaces a file
+pub fn upload_target(
+    data: &AppState<'_>,
+    dir: &Path,
+    name: &str,
+) -> Result<(PathBuf, Placed), HttpError> {
+    data.upload_types.check_name(name)?;
+    let path = dir.join(name);
+    let metadata = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok((path, Placed::Created)),
+    };
+    if metadata.is_dir() {
+        return Err(HttpError::Conflict);
+    }
+
+    // Uploads to drop boxes can't replace what others uploaded
+    let policy = match data.on_conflict {
+        ConflictPolicy::Overwrite if !data.mode.can_read() => ConflictPolicy::Rename,
+        policy => policy,
+    };
+    match policy {
+        ConflictPolicy::Overwrite => Ok((path, Placed::Overwritten)),
+        ConflictPolicy::Reject => Err(HttpError::Conflict),
+        ConflictPolicy::Rename => {
+            let name = Path::new(name);
+            let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = name
+                .extension()
+                .map(|extension| format!(".{}", extension.to_string_lossy()))
+                .unwrap_or_default();
+            (1..)
+                .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+                .find(|path| std::fs::symlink_metadata(path).is_err())
+                .map(|path| (path, Placed::Created))
+                .ok_or(HttpError::Conflict)
+        }
+    }
+}
+
+/// What the client says about a file it uploads
+#[derive(Default)]
+pub struct FileDetails {
+    /// Hex-encoded SHA-256 the contents need to have
+    pub sha256: Option<String>,
+    /// Modification time to give the stored file
+    pub modified: Option<SystemTime>,
+    /// Whether to unpack the file, which is an archive, instead of storing it
+    pub extract: bool,
+}
+
+/// What `store` wrote
+pub struct Stored {
+    /// Where the file ended up, which the conflict policy may have given another name
+    pub path: PathBuf,
+    pub placed: Placed,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the contents
+    pub sha256: String,
+}
+
+/// Writes `stream` to a file called `name` in `dir`, placed by the conflict policy, returning
+/// where it went, its size and checksum. The data goes to a hidden file in `dir` first, which is
+/// moved into place once everything has arrived and its type was checked, so interrupted,
+/// oversized or refused uploads never leave a partial file behind. Contents that don't match the
+/// SHA-256 in `details` are refused with a 422, and files that are locked without `tokens` having
+/// their tokens with a 423.
+pub async fn store<S, E>(
+    data: &AppState<'_>,
+    dir: &Path,
+    name: &str,
+    stream: S,
+    details: &FileDetails,
+    tokens: &LockTokens,
+) -> Result<Stored, HttpError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    // Fail early instead of after the whole file was sent
+    let (path, placed) = upload_target(data, dir, name)?;
+    let membership = matches!(placed, Placed::Created);
+    data.dav.check_writable(&path, false, membership, tokens)?;
+    let temp_path = dir.join(format!(".{}.{}.part", name, uuid::Uuid::new_v4()));
+
+    let result = write_temp_file(data, &temp_path, stream, details)
+        .await
+        .and_then(|written| {
+            data.upload_types
+                .check_contents(name, &temp_path)
+                .map(|()| written)
+        })
+        .and_then(|written| match details.modified {
+            Some(modified) => set_modified(&temp_path, modified)
+                .map(|()| written)
+                .map_err(io_error),
+            None => Ok(written),
+        });
+    let result = match result {
+        // Other uploads may have taken the name while this one was arriving
+        Ok(written) => match upload_target(data, dir, name) {
+            Ok(target) => place(data, &temp_path, dir, name, target, tokens)
+                .await
+                .map(|(path, placed)| Stored {
+                    path,
+                    placed,
+                    size: written.size,
+                    sha256: written.sha256,
+                }),
+            Err(http_error) => Err(http_error),
+        },
+        Err(http_error) => Err(http_error),
+    };
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+/// Moves the complete upload at `from` to `target`, which `upload_target` picked for `name` in
+/// `dir`. Files are only replaced where the conflict policy said so: when another upload took the
+/// name since, the policy is applied again. Locks on the target need their tokens in `tokens`.
+pub async fn place(
+    data: &AppState<'_>,
+    from: &Path,
+    dir: &Path,
+    name: &str,
+    mut target: (PathBuf, Placed),
+    tokens: &LockTokens,
+) -> Result<(PathBuf, Placed), HttpError> {
+    // Neither renaming nor linking works across file systems, so then the file is moved next to
+    // its target first
+    let mut moved: Option<PathBuf> = None;
+    let result = loop {
+        let from = moved.as_deref().unwrap_or(from);
+        let (path, placed) = target;
+        let membership = matches!(placed, Placed::Created);
+        if let Err(http_error) = data.dav.check_writable(&path, false, membership, tokens) {
+            break Err(http_error);
+        }
+        let result = match placed {
+            Placed::Overwritten => tokio::fs::rename(from, &path).await,
+            // Unlike renaming, linking never replaces what is there
+            Placed::Created => match tokio::fs::hard_link(from, &path).await {
+                Ok(()) => {
+                    let _ = tokio::fs::remove_file(from).await;
+                    Ok(())
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::AlreadyExists | io::ErrorKind::CrossesDevices
+                    ) =>
+                {
+                    Err(e)
+                }
+                // File systems without hard links
+                Err(_) => tokio::fs::rename(from, &path).await,
+            },
+        };
+        match result {
+            Ok(()) => break Ok((path, placed)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                match upload_target(data, dir, name) {
+                    Ok(next) => target = next,
+                    Err(http_error) => break Err(http_error),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices && moved.is_none() => {
+                let temp_path = dir.join(format!(".{}.{}.part", name, uuid::Uuid::new_v4()));
+                if let Err(http_error) =
+                    crate::rename::move_path(data, from, &temp_path, false, tokens).await
+                {
+                    break Err(http_error);
+                }
+                moved = Some(temp_path);
+                target = (path, placed);
             }
+            Err(e) => break Err(io_error(e)),
         }
+    };
+    if let (Err(_), Some(moved)) = (&result, &moved) {
+        let _ = tokio::fs::remove_file(moved).await;
+    }
+    result
+}
+
+/// What `write_temp_file` wrote
+struct Written {
+    size: u64,
+    /// Hex-encoded SHA-256 of the contents
+    sha256: String,
+}
+
+/// Writes `stream` to `temp_path`, checking its size and SHA-256
+async fn write_temp_file<S, E>(
+    data: &AppState<'_>,
+    temp_path: &Path,
+    stream: S,
+    details: &FileDetails,
+) -> Result<Written, HttpError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let mut file = tokio::fs::File::create(temp_path).await.map_err(io_error)?;
+    let mut size = 0;
+    let mut hasher = Sha256::new();
+    let mut stream = std::pin::pin!(stream);
+    while let Some(chunk) = stream.next().await {
+        // The client disconnected or sent a malformed body
+        let chunk = chunk.map_err(|_| HttpError::BadRequest)?;
+        size += chunk.len() as u64;
+        if data.max_upload_size.is_some_and(|max_size| size > max_size) {
+            return Err(HttpError::PayloadTooLarge);
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(io_error)?;
+    }
+    file.flush().await.map_err(io_error)?;
+    let sha256 = format!("{:x}", hasher.finalize());
+    if details
+        .sha256
+        .as_ref()
+        .is_some_and(|expected| *expected != sha256)
+    {
+        return Err(HttpError::UnprocessableEntity);
+    }
+    Ok(Written { size, sha256 })
+}
 
-        let mut response = HttpResponse::SeeOther();
-        response.append_header((LOCATION, req.path()));
-        Ok(response.finish())
+/// Hands a stored file to the upload hook, if there is one
+pub fn run_hook(
+    data: &AppState<'_>,
+    path: &Path,
+    size: u64,
+    sha256: Option<&str>,
+    source: &'static str,
+    user: Option<&str>,
+) {
+    if let Some(hook) = &data.on_upload {
+        hook.run(UploadEvent {
+            path: path.to_owned(),
+            web_path: data.web_path(path),
+            size,
+            sha256: sha256.map(str::to_owned),
+            source,
+            user: user.map(str::to_owned),
+        });
     }
+}
+
+pub fn set_modified(path: &Path, modified: SystemTime) -> io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(modified)
+}
 
-    match inner(&data, req, payload, web_path).await {
-        Ok(http_response) => http_response,
-        Err(e) => {
+fn io_error(e: io::Error) -> HttpError {
+    match e.kind() {
+        io::ErrorKind::NotFound => HttpError::Conflict,
+        io::ErrorKind::PermissionDenied => HttpError::Forbidden,
+        _ => {
             eprintln!("Upload error: {}", e);
-            crate::error::error_response(&data.hbs, crate::error::HttpError::InternalServerError)
+            HttpError::InternalServerError
         }
     }
 }
@@ -0,0 +1,114 @@
+use crate::error::HttpError;
+use std::{io::Read, path::Path};
+
+/// How much of the start of a file is looked at to detect its type
+const SNIFF_LEN: u64 = 8192;
+
+/// Which files can be uploaded, going by their extensions and by the types detected from their
+/// contents. Refused uploads get a 415.
+pub struct UploadTypes {
+    allow: Vec<String>,
+    deny: Vec<String>,
+    types: Vec<String>,
+}
+
+impl UploadTypes {
+    /// Extensions are given without the dot and types as MIME types, where `image/*` stands for
+    /// every image type. Empty lists don't limit anything.
+    pub fn new(allow: &[String], deny: &[String], types: &[String]) -> Self {
+        let normalize = |list: &[String]| {
+            list.iter()
+                .map(|item| item.trim().trim_start_matches('.').to_lowercase())
+                .collect()
+        };
+        UploadTypes {
+            allow: normalize(allow),
+            deny: normalize(deny),
+            types: normalize(types),
+        }
+    }
+
+    /// Checks the extension of a file about to be uploaded
+    pub fn check_name(&self, name: &str) -> Result<(), HttpError> {
+        let extension = extension(name);
+        let allowed = self.allow.is_empty()
+            || extension
+                .as_ref()
+                .is_some_and(|extension| self.allow.contains(extension));
+        let denied = extension.is_some_and(|extension| self.deny.contains(&extension));
+        if allowed && !denied {
+            Ok(())
+        } else {
+            Err(HttpError::UnsupportedMediaType)
+        }
+    }
+
+    /// Checks the contents of an upload called `name` that was stored at `path`. Contents that
+    /// aren't recognized are taken to be of the type the extension suggests.
+    pub fn check_contents(&self, name: &str, path: &Path) -> Result<(), HttpError> {
+        if self.allow.is_empty() && self.deny.is_empty() && self.types.is_empty() {
+            return Ok(());
+        }
+        let mut head = Vec::new();
+        std::fs::File::open(path)
+            .and_then(|file| file.take(SNIFF_LEN).read_to_end(&mut head))
+            .map_err(|e| {
+                eprintln!("Upload error: {}", e);
+                HttpError::InternalServerError
+            })?;
+        let detected = infer::get(&head);
+
+        // A refused kind of file doesn't get through by being renamed, under any of the extensions
+        // its type goes by
+        if let Some(kind) = detected {
+            let mut extensions = vec![kind.extension()];
+            extensions
+                .extend(mime_guess::get_mime_extensions_str(kind.mime_type()).unwrap_or_default());
+            let listed = |list: &[String]| {
+                extensions
+                    .iter()
+                    .any(|ext| list.iter().any(|item| item == ext))
+            };
+            if listed(&self.deny) || !(self.allow.is_empty() || listed(&self.allow)) {
+                return Err(HttpError::UnsupportedMediaType);
+            }
+        }
+        if self.types.is_empty() {
+            return Ok(());
+        }
+        let mime = match detected {
+            Some(kind) => kind.mime_type().to_owned(),
+            None => actix_files::file_extension_to_mime(&extension(name).unwrap_or_default())
+                .essence_str()
+                .to_owned(),
+        };
+        let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(top_level) => mime.split('/').next() == Some(top_level),
+            None => *pattern == mime,
+        };
+        if self.types.iter().any(matches) {
+            Ok(())
+        } else {
+            Err(HttpError::UnsupportedMediaType)
+        }
+    }
+
+    /// Value for the `accept` attribute of file inputs, if uploads are limited. Browsers only use
+    /// it to filter what is offered for picking.
+    pub fn accept(&self) -> Option<String> {
+        if !self.allow.is_empty() {
+            let extensions: Vec<_> = self.allow.iter().map(|ext| format!(".{}", ext)).collect();
+            Some(extensions.join(","))
+        } else if !self.types.is_empty() {
+            Some(self.types.join(","))
+        } else {
+            None
+        }
+    }
+}
+
+fn extension(name: &str) -> Option<String> {
+    Path::new(name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
@@ -0,0 +1,1598 @@
+//! WebDAV (RFC 4918) on top of the served directory, so that it can be mounted as a network
+//! drive. `PROPFIND`, `PROPPATCH`, `LOCK` and `UNLOCK` are handled here, along with the `If`
+//! header every method is checked against. The other methods are in `put`, `delete`, `mkdir`
+//! and `rename`. Locks and dead properties are only kept in memory.
+//!
+//! The tests at the end go through what the litmus test suite checks, in the same groups.
+
+use crate::{
+    error::{error_response, HttpError},
+    AppState,
+};
+use actix_web::{
+    http::header::{self, HttpDate},
+    web::{self, Bytes, Payload},
+    FromRequest, HttpRequest, HttpResponse,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use roxmltree::{Document, Node, NodeType};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+const DAV_NAMESPACE: &str = "DAV:";
+
+const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+
+/// Methods understood by `catch_all`, sent in `Allow`
+const ALLOWED_METHODS: &str =
+    "OPTIONS, GET, HEAD, POST, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK";
+
+/// Properties computed from the file system, which cannot be changed with `PROPPATCH`
+const LIVE_PROPERTIES: &[&str] = &[
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "lockdiscovery",
+    "resourcetype",
+    "supportedlock",
+];
+
+const SUPPORTED_LOCK: &str = "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+    <D:locktype><D:write/></D:locktype></D:lockentry>\
+    <D:lockentry><D:lockscope><D:shared/></D:lockscope>\
+    <D:locktype><D:write/></D:locktype></D:lockentry>";
+
+/// Lock timeout in seconds if the client doesn't ask for one
+const DEFAULT_LOCK_TIMEOUT: u64 = 3600;
+
+/// Longest lock timeout in seconds, also used for `Timeout: Infinite`
+const MAX_LOCK_TIMEOUT: u64 = 86400;
+
+/// Characters escaped in the path segments of hrefs
+const HREF_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Locks and dead properties, both of which are only kept in memory
+#[derive(Default)]
+pub struct DavState {
+    locks: Mutex<Vec<Lock>>,
+    properties: Mutex<HashMap<PathBuf, Vec<DeadProperty>>>,
+}
+
+/// Lock tokens that a request submitted in its `If` header, and who sent it. Everything that
+/// changes files checks the locks with them, whether the request is a WebDAV one, a form or an
+/// upload.
+#[derive(Default)]
+pub struct LockTokens {
+    tokens: Vec<String>,
+    user: Option<String>,
+}
+
+impl LockTokens {
+    /// Headers that can't be parsed submit no tokens; WebDAV requests are refused for them before.
+    pub fn of(req: &HttpRequest) -> Self {
+        let tokens = match if_header(req) {
+            Ok(Some(lists)) => submitted_tokens(&lists)
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            _ => Vec::new(),
+        };
+        LockTokens {
+            tokens,
+            user: crate::auth::user(req),
+        }
+    }
+
+    fn hold(&self, lock: &Lock) -> bool {
+        self.tokens
+            .iter()
+            .any(|token| lock.is_held(token, self.user.as_deref()))
+    }
+}
+
+#[derive(Clone)]
+struct Lock {
+    token: String,
+    root: PathBuf,
+    /// URL of `root` as sent by the client that locked it
+    href: String,
+    exclusive: bool,
+    infinite: bool,
+    /// `owner` element of the request, serialized
+    owner: Option<String>,
+    /// Who created the lock, if users have to log in. Only they can use its token.
+    user: Option<String>,
+    timeout: u64,
+    expires: Instant,
+}
+
+impl Lock {
+    fn covers(&self, path: &Path) -> bool {
+        path == self.root || (self.infinite && path.starts_with(&self.root))
+    }
+
+    /// Whether `user` submitting `token` counts as holding the lock
+    fn is_held(&self, token: &str, user: Option<&str>) -> bool {
+        self.token == token && self.user.as_deref() == user
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct PropertyName {
+    namespace: String,
+    name: String,
+}
+
+impl PropertyName {
+    fn dav(name: &str) -> Self {
+        PropertyName {
+            namespace: DAV_NAMESPACE.to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn of(node: Node<'_, '_>) -> Self {
+        PropertyName {
+            namespace: node.tag_name().namespace().unwrap_or("").to_owned(),
+            name: node.tag_name().name().to_owned(),
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        self.namespace == DAV_NAMESPACE && LIVE_PROPERTIES.contains(&self.name.as_str())
+    }
+}
+
+#[derive(Clone)]
+struct DeadProperty {
+    name: PropertyName,
+    /// Contents of the property element, serialized
+    value: String,
+}
+
+impl DavState {
+    /// The lock table, without the locks that have expired
+    fn locks(&self) -> MutexGuard<'_, Vec<Lock>> {
+        let mut locks = self.locks.lock().unwrap();
+        let now = Instant::now();
+        locks.retain(|lock| lock.expires > now);
+        locks
+    }
+
+    fn properties(&self) -> MutexGuard<'_, HashMap<PathBuf, Vec<DeadProperty>>> {
+        self.properties.lock().unwrap()
+    }
+
+    /// Forgets the locks and properties of `path` and everything under it
+    pub fn removed(&self, path: &Path) {
+        self.locks().retain(|lock| !lock.root.starts_with(path));
+        self.properties()
+            .retain(|property_path, _| !property_path.starts_with(path));
+    }
+
+    /// Moves the properties of `from` and everything under it to `to`. Locks stay behind and are
+    /// dropped.
+    pub fn moved(&self, from: &Path, to: &Path) {
+        self.removed(to);
+        self.locks().retain(|lock| !lock.root.starts_with(from));
+        let mut properties = self.properties();
+        let moved: Vec<_> = properties
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        for path in moved {
+            if let (Some(values), Ok(rest)) = (properties.remove(&path), path.strip_prefix(from)) {
+                properties.insert(to.join(rest), values);
+            }
+        }
+    }
+
+    /// Copies the properties of `from` and everything under it to `to`
+    pub fn copied(&self, from: &Path, to: &Path) {
+        self.removed(to);
+        let mut properties = self.properties();
+        let copied: Vec<_> = properties
+            .iter()
+            .filter_map(|(path, values)| {
+                let rest = path.strip_prefix(from).ok()?;
+                Some((to.join(rest), values.clone()))
+            })
+            .collect();
+        properties.extend(copied);
+    }
+
+    /// Checks that every lock on `path`, or with `descendants` also under it, has its token in
+    /// `tokens`. One token is enough for several shared locks on the same resource.
+    fn check_locks(
+        &self,
+        path: &Path,
+        descendants: bool,
+        tokens: &LockTokens,
+    ) -> Result<(), HttpError> {
+        let locks = self.locks();
+        let submitted = |lock: &Lock| tokens.hold(lock);
+        for lock in locks
+            .iter()
+            .filter(|lock| lock.covers(path) || (descendants && lock.root.starts_with(path)))
+        {
+            if !locks
+                .iter()
+                .any(|other| other.root == lock.root && submitted(other))
+            {
+                return Err(HttpError::Locked);
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuses to change `path`, or with `descendants` also what is under it, without the tokens
+    /// of the locks on it. With `membership`, `path` is added or removed, which changes the
+    /// directory it is in too.
+    pub fn check_writable(
+        &self,
+        path: &Path,
+        descendants: bool,
+        membership: bool,
+        tokens: &LockTokens,
+    ) -> Result<(), HttpError> {
+        self.check_locks(path, descendants, tokens)?;
+        match path.parent() {
+            Some(parent) if membership => self.check_locks(parent, false, tokens),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Handles `OPTIONS` requests, telling clients that WebDAV class 1 and 2 are supported.
+pub fn options() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("DAV", "1, 2"))
+        .insert_header((header::ALLOW, ALLOWED_METHODS))
+        // Makes Windows use WebDAV instead of FrontPage extensions
+        .insert_header(("MS-Author-Via", "DAV"))
+        .finish()
+}
+
+enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+fn depth(req: &HttpRequest) -> Result<Option<Depth>, HttpError> {
+    match req.headers().get("Depth").map(|depth| depth.as_bytes()) {
+        None => Ok(None),
+        Some(b"0") => Ok(Some(Depth::Zero)),
+        Some(b"1") => Ok(Some(Depth::One)),
+        Some(depth) if depth.eq_ignore_ascii_case(b"infinity") => Ok(Some(Depth::Infinity)),
+        Some(_) => Err(HttpError::BadRequest),
+    }
+}
+
+enum PropertyRequest {
+    AllProp,
+    PropName,
+    Prop(Vec<PropertyName>),
+}
+
+/// Handles `PROPFIND` requests for the resource at `web_path` and, with `Depth: 1`, the entries
+/// of a directory. Infinite depth is refused.
+pub async fn propfind(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    payload: Option<Payload>,
+    web_path: String,
+) -> HttpResponse {
+    match propfind_inner(&data, req, payload, &web_path).await {
+        Ok(http_response) => http_response,
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+async fn propfind_inner(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    payload: Option<Payload>,
+    web_path: &str,
+) -> Result<HttpResponse, HttpError> {
+    let local_path = data.local_path(web_path).ok_or(HttpError::NotFound)?;
+    let metadata = tokio::fs::metadata(&local_path)
+        .await
+        .map_err(|_| HttpError::NotFound)?;
+    let body = read_body(req, payload).await?;
+    let request = match parse_body(&body)? {
+        None => PropertyRequest::AllProp,
+        Some(document) => {
+            let root = dav_element(document.root_element(), "propfind")?;
+            match root.children().find(Node::is_element) {
+                Some(child) if is_dav(child, "allprop") => PropertyRequest::AllProp,
+                Some(child) if is_dav(child, "propname") => PropertyRequest::PropName,
+                Some(child) if is_dav(child, "prop") => PropertyRequest::Prop(
+                    child
+                        .children()
+                        .filter(Node::is_element)
+                        .map(PropertyName::of)
+                        .collect(),
+                ),
+                _ => return Err(HttpError::BadRequest),
+            }
+        }
+    };
+    let depth = depth(req)?.unwrap_or(Depth::Infinity);
+    if let Depth::Infinity = depth {
+        return Ok(HttpResponse::Forbidden()
+            .content_type("application/xml; charset=utf-8")
+            .body(format!(
+                "{}<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>",
+                XML_DECLARATION
+            )));
+    }
+
+    let user = crate::auth::user(req);
+    let user = user.as_deref();
+    let href = href(web_path, metadata.is_dir());
+    let mut xml = format!("{}<D:multistatus xmlns:D=\"DAV:\">", XML_DECLARATION);
+    write_properties(
+        &mut xml,
+        data,
+        user,
+        &href,
+        &local_path,
+        &metadata,
+        &request,
+    );
+    if let (Depth::One, true) = (depth, metadata.is_dir()) {
+        let visibility = crate::acl::visibility(data, user);
+        let entries = crate::index::sorted_entries(&visibility, &local_path, None)
+            .await
+            .map_err(|_| HttpError::InternalServerError)?;
+        for (_, file_name, entry) in entries {
+            // Entries that disappeared since being listed are left out
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let mut entry_href = href.clone();
+            entry_href.push_str(&utf8_percent_encode(&file_name, HREF_SEGMENT).to_string());
+            if metadata.is_dir() {
+                entry_href.push('/');
+            }
+            write_properties(
+                &mut xml,
+                data,
+                user,
+                &entry_href,
+                &entry.path(),
+                &metadata,
+                &request,
+            );
+        }
+    }
+    xml.push_str("</D:multistatus>");
+
+    Ok(multi_status(xml))
+}
+
+/// Writes the `response` element of one resource, as `user` gets to see it
+fn write_properties(
+    xml: &mut String,
+    data: &AppState<'_>,
+    user: Option<&str>,
+    href: &str,
+    local_path: &Path,
+    metadata: &std::fs::Metadata,
+    request: &PropertyRequest,
+) {
+    let mut found = Vec::new();
+    for name in LIVE_PROPERTIES {
+        let name = PropertyName::dav(name);
+        if let Some(value) = live_property(data, user, &name, local_path, metadata) {
+            found.push((name, value));
+        }
+    }
+    let dead_properties = data
+        .dav
+        .properties()
+        .get(local_path)
+        .cloned()
+        .unwrap_or_default();
+    found.extend(
+        dead_properties
+            .into_iter()
+            .map(|property| (property.name, property.value)),
+    );
+
+    let _ = write!(xml, "<D:response><D:href>{}</D:href>", escape(href));
+    match request {
+        PropertyRequest::AllProp => write_propstat(xml, &found, "200 OK"),
+        PropertyRequest::PropName => {
+            let names: Vec<_> = found
+                .into_iter()
+                .map(|(name, _)| (name, String::new()))
+                .collect();
+            write_propstat(xml, &names, "200 OK");
+        }
+        PropertyRequest::Prop(names) => {
+            let (mut present, mut missing) = (Vec::new(), Vec::new());
+            for name in names {
+                match found.iter().find(|(found_name, _)| found_name == name) {
+                    Some(property) => present.push(property.clone()),
+                    None => missing.push((name.clone(), String::new())),
+                }
+            }
+            write_propstat(xml, &present, "200 OK");
+            write_propstat(xml, &missing, "404 Not Found");
+        }
+    }
+    xml.push_str("</D:response>");
+}
+
+/// Value of a live property, or `None` if the resource doesn't have it. Lock tokens are only
+/// shown to the user that holds them.
+fn live_property(
+    data: &AppState<'_>,
+    user: Option<&str>,
+    name: &PropertyName,
+    local_path: &Path,
+    metadata: &std::fs::Metadata,
+) -> Option<String> {
+    let value = match name.name.as_str() {
+        "displayname" => {
+            let name = local_path
+                .file_name()
+                .or(data.serve_from.file_name())?
+                .to_string_lossy();
+            escape(&name)
+        }
+        "getcontentlength" if metadata.is_file() => metadata.len().to_string(),
+        "getcontenttype" if metadata.is_file() => {
+            let extension = local_path
+                .extension()
+                .map(|extension| extension.to_string_lossy())
+                .unwrap_or_default();
+            escape(actix_files::file_extension_to_mime(&extension).as_ref())
+        }
+        "getetag" if metadata.is_file() => escape(&etag(metadata)?),
+        "getlastmodified" => HttpDate::from(metadata.modified().ok()?).to_string(),
+        "lockdiscovery" => {
+            let mut value = String::new();
+            for lock in data
+                .dav
+                .locks()
+                .iter()
+                .filter(|lock| lock.covers(local_path))
+            {
+                write_active_lock(&mut value, lock, lock.user.as_deref() == user);
+            }
+            value
+        }
+        "resourcetype" if metadata.is_dir() => "<D:collection/>".to_owned(),
+        "resourcetype" => String::new(),
+        "supportedlock" => SUPPORTED_LOCK.to_owned(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Same entity tag as the one actix-files sends with the file
+fn etag(metadata: &std::fs::Metadata) -> Option<String> {
+    #[cfg(unix)]
+    let ino = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let ino = 0;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "\"{:x}:{:x}:{:x}:{:x}\"",
+        ino,
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    ))
+}
+
+/// Handles `PROPPATCH` requests. Changes to dead properties are applied all together, and none
+/// are if any of them targets a live property.
+pub async fn proppatch(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    payload: Option<Payload>,
+    web_path: String,
+) -> HttpResponse {
+    match proppatch_inner(&data, req, payload, &web_path).await {
+        Ok(http_response) => http_response,
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+async fn proppatch_inner(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    payload: Option<Payload>,
+    web_path: &str,
+) -> Result<HttpResponse, HttpError> {
+    let local_path = data.local_path(web_path).ok_or(HttpError::NotFound)?;
+    let metadata = tokio::fs::metadata(&local_path)
+        .await
+        .map_err(|_| HttpError::NotFound)?;
+    let body = read_body(req, payload).await?;
+    let document = parse_body(&body)?.ok_or(HttpError::BadRequest)?;
+    let root = dav_element(document.root_element(), "propertyupdate")?;
+
+    // Each change is a property with its new value, or `None` to remove it
+    let mut changes = Vec::new();
+    for instruction in root.children().filter(Node::is_element) {
+        let set = match instruction.tag_name().name() {
+            "set" if is_dav(instruction, "set") => true,
+            "remove" if is_dav(instruction, "remove") => false,
+            _ => return Err(HttpError::BadRequest),
+        };
+        for prop in instruction.children().filter(|node| is_dav(*node, "prop")) {
+            for property in prop.children().filter(Node::is_element) {
+                let value = set.then(|| serialize_children(property));
+                changes.push((PropertyName::of(property), value));
+            }
+        }
+    }
+
+    let href = href(web_path, metadata.is_dir());
+    let mut xml = format!(
+        "{}<D:multistatus xmlns:D=\"DAV:\"><D:response><D:href>{}</D:href>",
+        XML_DECLARATION,
+        escape(&href)
+    );
+    let (protected, changeable): (Vec<_>, Vec<_>) = changes
+        .iter()
+        .map(|(name, _)| (name.clone(), String::new()))
+        .partition(|(name, _)| name.is_live());
+    if !protected.is_empty() {
+        write_propstat(&mut xml, &protected, "403 Forbidden");
+        write_propstat(&mut xml, &changeable, "424 Failed Dependency");
+    } else {
+        let mut properties = data.dav.properties();
+        let values = properties.entry(local_path).or_default();
+        for (name, value) in changes {
+            values.retain(|property| property.name != name);
+            if let Some(value) = value {
+                values.push(DeadProperty { name, value });
+            }
+        }
+        write_propstat(&mut xml, &changeable, "200 OK");
+    }
+    xml.push_str("</D:response></D:multistatus>");
+
+    Ok(multi_status(xml))
+}
+
+/// Handles `LOCK` requests, creating an empty file if nothing exists at `web_path` yet. A request
+/// without a body refreshes the lock given in the `If` header.
+pub async fn lock(
+    data: web::Data<AppState<'_>>,
+    req: &HttpRequest,
+    payload: Option<Payload>,
+    web_path: String,
+) -> HttpResponse {
+    match lock_inner(&data, req, payload, &web_path).await {
+        Ok(http_response) => http_response,
+        Err(http_error) => error_response(&data.hbs, http_error),
+    }
+}
+
+async fn lock_inner(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    payload: Option<Payload>,
+    web_path: &str,
+) -> Result<HttpResponse, HttpError> {
+    let local_path = data.local_path(web_path).ok_or(HttpError::BadRequest)?;
+    let timeout = lock_timeout(req);
+    let body = read_body(req, payload).await?;
+
+    let document = match parse_body(&body)? {
+        Some(document) => document,
+        None => {
+            let lists = if_header(req)?.ok_or(HttpError::BadRequest)?;
+            let tokens = submitted_tokens(&lists);
+            let user = crate::auth::user(req);
+            let mut locks = data.dav.locks();
+            let lock = locks
+                .iter_mut()
+                .find(|lock| {
+                    lock.covers(&local_path)
+                        && tokens
+                            .iter()
+                            .any(|token| lock.is_held(token, user.as_deref()))
+                })
+                .ok_or(HttpError::PreconditionFailed)?;
+            lock.timeout = timeout;
+            lock.expires = Instant::now() + Duration::from_secs(timeout);
+            return Ok(lock_response(HttpResponse::Ok(), lock));
+        }
+    };
+
+    let root = dav_element(document.root_element(), "lockinfo")?;
+    let mut exclusive = None;
+    let mut owner = None;
+    for child in root.children().filter(Node::is_element) {
+        if is_dav(child, "lockscope") {
+            exclusive = match child.children().find(Node::is_element) {
+                Some(scope) if is_dav(scope, "exclusive") => Some(true),
+                Some(scope) if is_dav(scope, "shared") => Some(false),
+                _ => return Err(HttpError::BadRequest),
+            };
+        } else if is_dav(child, "locktype") {
+            // Write locks are the only kind there is
+            if !child.children().any(|kind| is_dav(kind, "write")) {
+                return Err(HttpError::BadRequest);
+            }
+        } else if is_dav(child, "owner") {
+            owner = Some(format!("<D:owner>{}</D:owner>", serialize_children(child)));
+        }
+    }
+    let exclusive = exclusive.ok_or(HttpError::BadRequest)?;
+    let infinite = match depth(req)? {
+        None | Some(Depth::Infinity) => true,
+        Some(Depth::Zero) => false,
+        Some(Depth::One) => return Err(HttpError::BadRequest),
+    };
+
+    let mut locks = data.dav.locks();
+    let conflicts = locks.iter().any(|lock| {
+        let overlaps = lock.covers(&local_path) || (infinite && lock.root.starts_with(&local_path));
+        overlaps && (exclusive || lock.exclusive)
+    });
+    if conflicts {
+        return Err(HttpError::Locked);
+    }
+
+    // Locking a path that doesn't exist yet creates an empty file there
+    let created = match std::fs::symlink_metadata(&local_path) {
+        Ok(_) => false,
+        Err(_) => {
+            let name = local_path
+                .file_name()
+                .ok_or(HttpError::Conflict)?
+                .to_string_lossy();
+            if sanitize_filename::sanitize(&name) != name || data.visibility.is_hidden(&name) {
+                return Err(HttpError::BadRequest);
+            }
+            data.upload_types.check_name(&name)?;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&local_path)
+                .map_err(|_| HttpError::Conflict)?;
+            true
+        }
+    };
+
+    let lock = Lock {
+        token: format!("opaquelocktoken:{}", uuid::Uuid::new_v4()),
+        href: href(web_path, local_path.is_dir()),
+        root: local_path,
+        exclusive,
+        infinite,
+        owner,
+        user: crate::auth::user(req),
+        timeout,
+        expires: Instant::now() + Duration::from_secs(timeout),
+    };
+    let mut builder = if created {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
+    builder.insert_header(("Lock-Token", format!("<{}>", lock.token)));
+    let http_response = lock_response(builder, &lock);
+    locks.push(lock);
+    Ok(http_response)
+}
+
+/// Handles `UNLOCK` requests for the lock given in the `Lock-Token` header.
+pub fn unlock(data: web::Data<AppState<'_>>, req: &HttpRequest, web_path: String) -> HttpResponse {
+    let token = req
+        .headers()
+        .get("Lock-Token")
+        .and_then(|token| token.to_str().ok())
+        .map(|token| token.trim().trim_start_matches('<').trim_end_matches('>'));
+    let (local_path, token) = match (data.local_path(&web_path), token) {
+        (Some(local_path), Some(token)) => (local_path, token),
+        _ => return error_response(&data.hbs, HttpError::BadRequest),
+    };
+
+    let user = crate::auth::user(req);
+    let mut locks = data.dav.locks();
+    match locks
+        .iter()
+        .position(|lock| lock.is_held(token, user.as_deref()) && lock.covers(&local_path))
+    {
+        Some(i) => {
+            locks.remove(i);
+            HttpResponse::NoContent().finish()
+        }
+        None => error_response(&data.hbs, HttpError::Conflict),
+    }
+}
+
+/// Seconds asked for in the `Timeout` header
+fn lock_timeout(req: &HttpRequest) -> u64 {
+    req.headers()
+        .get("Timeout")
+        .and_then(|timeout| timeout.to_str().ok())
+        .and_then(|timeout| {
+            timeout.split(',').map(str::trim).find_map(|timeout| {
+                if timeout.eq_ignore_ascii_case("Infinite") {
+                    Some(MAX_LOCK_TIMEOUT)
+                } else {
+                    timeout.strip_prefix("Second-")?.parse().ok()
+                }
+            })
+        })
+        .map_or(DEFAULT_LOCK_TIMEOUT, |timeout: u64| {
+            timeout.min(MAX_LOCK_TIMEOUT)
+        })
+}
+
+fn lock_response(mut builder: actix_web::HttpResponseBuilder, lock: &Lock) -> HttpResponse {
+    let mut xml = format!(
+        "{}<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>",
+        XML_DECLARATION
+    );
+    write_active_lock(&mut xml, lock, true);
+    xml.push_str("</D:lockdiscovery></D:prop>");
+    builder
+        .content_type("application/xml; charset=utf-8")
+        .body(xml)
+}
+
+/// Writes `lock` for `lockdiscovery`, leaving out its token unless `with_token` is set
+fn write_active_lock(xml: &mut String, lock: &Lock, with_token: bool) {
+    // Rounded up so that a fresh lock shows the timeout it was given
+    let remaining = lock.expires.saturating_duration_since(Instant::now());
+    let remaining = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+    let _ = write!(
+        xml,
+        "<D:activelock><D:locktype><D:write/></D:locktype>\
+        <D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>{}\
+        <D:timeout>Second-{}</D:timeout>{}\
+        <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        if lock.exclusive {
+            "exclusive"
+        } else {
+            "shared"
+        },
+        if lock.infinite { "infinity" } else { "0" },
+        lock.owner.as_deref().unwrap_or(""),
+        remaining.min(lock.timeout),
+        if with_token {
+            format!(
+                "<D:locktoken><D:href>{}</D:href></D:locktoken>",
+                escape(&lock.token)
+            )
+        } else {
+            String::new()
+        },
+        escape(&lock.href),
+    );
+}
+
+/// One list of conditions of the `If` header, tagged with the resource it applies to
+struct IfList {
+    resource: Option<String>,
+    /// Each condition is a lock token or entity tag, negated if the flag is set
+    conditions: Vec<(bool, Condition)>,
+}
+
+enum Condition {
+    Token(String),
+    ETag(String),
+}
+
+fn if_header(req: &HttpRequest) -> Result<Option<Vec<IfList>>, HttpError> {
+    match req.headers().get("If") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(parse_if)
+            .map(Some)
+            .ok_or(HttpError::BadRequest),
+        None => Ok(None),
+    }
+}
+
+/// Parses the `If` header of RFC 4918, section 10.4
+fn parse_if(header: &str) -> Option<Vec<IfList>> {
+    let mut lists = Vec::new();
+    let mut resource = None;
+    let mut rest = header.trim_start();
+    while !rest.is_empty() {
+        if let Some(tagged) = rest.strip_prefix('<') {
+            let end = tagged.find('>')?;
+            resource = Some(tagged[..end].to_owned());
+            rest = &tagged[end + 1..];
+        } else if let Some(list) = rest.strip_prefix('(') {
+            let end = list.find(')')?;
+            let mut conditions = Vec::new();
+            let mut inner = list[..end].trim_start();
+            while !inner.is_empty() {
+                let not = match inner.strip_prefix("Not") {
+                    Some(after) => {
+                        inner = after.trim_start();
+                        true
+                    }
+                    None => false,
+                };
+                let (condition, after) = if let Some(token) = inner.strip_prefix('<') {
+                    let end = token.find('>')?;
+                    (Condition::Token(token[..end].to_owned()), &token[end + 1..])
+                } else if let Some(etag) = inner.strip_prefix('[') {
+                    let end = etag.find(']')?;
+                    (Condition::ETag(etag[..end].to_owned()), &etag[end + 1..])
+                } else {
+                    return None;
+                };
+                conditions.push((not, condition));
+                inner = after.trim_start();
+            }
+            if conditions.is_empty() {
+                return None;
+            }
+            lists.push(IfList {
+                resource: resource.clone(),
+                conditions,
+            });
+            rest = &list[end + 1..];
+        } else {
+            return None;
+        }
+        rest = rest.trim_start();
+    }
+    (!lists.is_empty()).then_some(lists)
+}
+
+/// Every lock token in the `If` header, all of which count as submitted by the client
+fn submitted_tokens(lists: &[IfList]) -> Vec<&str> {
+    lists
+        .iter()
+        .flat_map(|list| &list.conditions)
+        .filter_map(|(_, condition)| match condition {
+            Condition::Token(token) => Some(token.as_str()),
+            Condition::ETag(_) => None,
+        })
+        .collect()
+}
+
+/// Whether any of the lists holds, each for its own resource or the requested one. Lock tokens
+/// only match for the user that holds the lock.
+fn evaluate_if(
+    data: &AppState<'_>,
+    user: Option<&str>,
+    local_path: &Path,
+    lists: &[IfList],
+) -> bool {
+    lists.iter().any(|list| {
+        let path = match &list.resource {
+            Some(resource) => match data.local_path_from_url(resource) {
+                Some(path) => path,
+                None => return false,
+            },
+            None => local_path.to_owned(),
+        };
+        let etag = std::fs::metadata(&path)
+            .ok()
+            .and_then(|metadata| etag(&metadata));
+        list.conditions.iter().all(|(not, condition)| {
+            let holds = match condition {
+                Condition::Token(token) => data
+                    .dav
+                    .locks()
+                    .iter()
+                    .any(|lock| lock.is_held(token, user) && lock.covers(&path)),
+                Condition::ETag(expected) => etag.as_ref() == Some(expected),
+            };
+            holds != *not
+        })
+    })
+}
+
+/// Evaluates the `If` header and checks that the request has the tokens of the locks on
+/// everything it changes, before its body is read. Adding or removing an entry also changes the
+/// directory it is in. What changes files checks the locks again when it does.
+pub fn check_request(
+    data: &AppState<'_>,
+    req: &HttpRequest,
+    web_path: &str,
+) -> Result<(), HttpError> {
+    let local_path = match data.local_path(web_path) {
+        Some(local_path) => local_path,
+        // The handlers report paths outside of the served directory
+        None => return Ok(()),
+    };
+    let tokens = LockTokens::of(req);
+    if let Some(lists) = if_header(req)? {
+        if !evaluate_if(data, tokens.user.as_deref(), &local_path, &lists) {
+            return Err(HttpError::PreconditionFailed);
+        }
+    }
+
+    let check_target = |path: &Path, descendants: bool, membership: bool| {
+        data.dav
+            .check_writable(path, descendants, membership, &tokens)
+    };
+    let exists = std::fs::symlink_metadata(&local_path).is_ok();
+    match req.method().as_str() {
+        "PUT" | "MKCOL" => check_target(&local_path, false, !exists),
+        "PROPPATCH" => check_target(&local_path, false, false),
+        "DELETE" => check_target(&local_path, true, true),
+        "MOVE" | "COPY" => {
+            if req.method().as_str() == "MOVE" {
+                check_target(&local_path, true, true)?;
+            }
+            match crate::rename::destination(data, req) {
+                Some(destination) => check_target(&destination, true, true),
+                None => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn read_body(req: &HttpRequest, payload: Option<Payload>) -> Result<Bytes, HttpError> {
+    match payload {
+        Some(payload) => Bytes::from_request(req, &mut payload.into_inner())
+            .await
+            .map_err(|_| HttpError::BadRequest),
+        None => Ok(Bytes::new()),
+    }
+}
+
+/// Parses an XML request body, which may be left empty
+fn parse_body(body: &[u8]) -> Result<Option<Document<'_>>, HttpError> {
+    let text = std::str::from_utf8(body).map_err(|_| HttpError::BadRequest)?;
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    Document::parse(text)
+        .map(Some)
+        .map_err(|_| HttpError::BadRequest)
+}
+
+fn is_dav(node: Node<'_, '_>, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().namespace() == Some(DAV_NAMESPACE)
+        && node.tag_name().name() == name
+}
+
+fn dav_element<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &str,
+) -> Result<Node<'a, 'input>, HttpError> {
+    if is_dav(node, name) {
+        Ok(node)
+    } else {
+        Err(HttpError::BadRequest)
+    }
+}
+
+fn multi_status(xml: String) -> HttpResponse {
+    HttpResponse::MultiStatus()
+        .content_type("application/xml; charset=utf-8")
+        .body(xml)
+}
+
+/// Writes a `propstat` element with the properties and their values, if there are any
+fn write_propstat(xml: &mut String, properties: &[(PropertyName, String)], status: &str) {
+    if properties.is_empty() {
+        return;
+    }
+    xml.push_str("<D:propstat><D:prop>");
+    for (name, value) in properties {
+        let tag = if name.namespace == DAV_NAMESPACE {
+            format!("D:{}", name.name)
+        } else {
+            name.name.clone()
+        };
+        xml.push('<');
+        xml.push_str(&tag);
+        if name.namespace != DAV_NAMESPACE {
+            let _ = write!(xml, " xmlns=\"{}\"", escape(&name.namespace));
+        }
+        if value.is_empty() {
+            xml.push_str("/>");
+        } else {
+            let _ = write!(xml, ">{}</{}>", value, tag);
+        }
+    }
+    let _ = write!(
+        xml,
+        "</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+        status
+    );
+}
+
+/// Serializes the contents of an element, declaring the namespace of every element so that the
+/// result can be put anywhere
+fn serialize_children(node: Node<'_, '_>) -> String {
+    let mut xml = String::new();
+    for child in node.children() {
+        match child.node_type() {
+            NodeType::Element => {
+                let name = child.tag_name().name();
+                let _ = write!(
+                    xml,
+                    "<{} xmlns=\"{}\"",
+                    name,
+                    escape(child.tag_name().namespace().unwrap_or(""))
+                );
+                for (i, attribute) in child.attributes().enumerate() {
+                    match attribute.namespace() {
+                        Some(namespace) => {
+                            let _ = write!(
+                                xml,
+                                " xmlns:a{i}=\"{}\" a{i}:{}=\"{}\"",
+                                escape(namespace),
+                                attribute.name(),
+                                escape(attribute.value())
+                            );
+                        }
+                        None => {
+                            let _ = write!(
+                                xml,
+                                " {}=\"{}\"",
+                                attribute.name(),
+                                escape(attribute.value())
+                            );
+                        }
+                    }
+                }
+                if child.has_children() {
+                    let _ = write!(xml, ">{}</{}>", serialize_children(child), name);
+                } else {
+                    xml.push_str("/>");
+                }
+            }
+            NodeType::Text => xml.push_str(&escape(child.text().unwrap_or(""))),
+            _ => (),
+        }
+    }
+    xml
+}
+
+/// URL of a resource, with a trailing slash for directories
+pub fn href(web_path: &str, is_dir: bool) -> String {
+    let mut href = String::new();
+    for segment in web_path.split('/').filter(|segment| !segment.is_empty()) {
+        href.push('/');
+        href.push_str(&utf8_percent_encode(segment, HREF_SEGMENT).to_string());
+    }
+    if is_dir || href.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AppState, Args};
+    use actix_web::{
+        http::{
+            header::{self, HeaderMap},
+            Method, StatusCode,
+        },
+        test, web, App,
+    };
+    use clap::Parser;
+    use std::path::PathBuf;
+
+    const LOCK_INFO: &str = r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype><D:owner><D:href>litmus</D:href></D:owner></D:lockinfo>"#;
+
+    const PROPPATCH_SET: &str = r#"<?xml version="1.0"?><D:propertyupdate xmlns:D="DAV:" xmlns:Z="http://example.com/ns"><D:set><D:prop><Z:color>red<Z:b attr="1">x &amp; y</Z:b></Z:color><nons xmlns="">v</nons></D:prop></D:set></D:propertyupdate>"#;
+
+    /// Server on a directory of its own, which is deleted afterwards
+    struct Server {
+        root: PathBuf,
+        data: web::Data<AppState<'static>>,
+    }
+
+    struct Response {
+        status: StatusCode,
+        headers: HeaderMap,
+        body: String,
+    }
+
+    impl Server {
+        fn new(args: &[&str]) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("file-server-rs-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir(&root).unwrap();
+            let root_arg = root.to_string_lossy().into_owned();
+            let resumable_dir = root.join(".tus").to_string_lossy().into_owned();
+            let mut all_args = vec![
+                "file-server-rs",
+                "--root",
+                &root_arg,
+                "--resumable-dir",
+                &resumable_dir,
+                "--allow-delete",
+            ];
+            all_args.extend_from_slice(args);
+            let data = web::Data::new(AppState::new(&Args::parse_from(all_args)));
+            Server { root, data }
+        }
+
+        async fn request(
+            &self,
+            method: &str,
+            path: &str,
+            headers: &[(&str, &str)],
+            body: &str,
+        ) -> Response {
+            let app = test::init_service(
+                App::new()
+                    .app_data(self.data.clone())
+                    .wrap(crate::auth::BasicAuth)
+                    .default_service(web::to(crate::catch_all)),
+            )
+            .await;
+            let mut req = test::TestRequest::default()
+                .method(Method::from_bytes(method.as_bytes()).unwrap())
+                .uri(path);
+            for &header in headers {
+                req = req.insert_header(header);
+            }
+            if !body.is_empty() {
+                req = req.insert_header((header::CONTENT_LENGTH, body.len()));
+            }
+            let res = test::call_service(&app, req.set_payload(body.to_owned()).to_request()).await;
+            let status = res.status();
+            let headers = res.headers().clone();
+            let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            Response {
+                status,
+                headers,
+                body,
+            }
+        }
+
+        async fn status(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> u16 {
+            self.request(method, path, headers, "")
+                .await
+                .status
+                .as_u16()
+        }
+
+        async fn put(&self, path: &str, headers: &[(&str, &str)], body: &str) -> u16 {
+            self.request("PUT", path, headers, body)
+                .await
+                .status
+                .as_u16()
+        }
+
+        async fn get(&self, path: &str) -> String {
+            self.request("GET", path, &[], "").await.body
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[actix_web::test]
+    async fn basic() {
+        let server = Server::new(&[]);
+        let options = server.request("OPTIONS", "/", &[], "").await;
+        assert_eq!(options.headers.get("DAV").unwrap(), "1, 2");
+
+        assert_eq!(server.put("/res", &[], "hello").await, 201);
+        assert_eq!(server.put("/res", &[], "hello2").await, 204);
+        assert_eq!(server.get("/res").await, "hello2");
+        assert_eq!(server.put("/%E2%82%AC%20res", &[], "x").await, 201);
+        assert_eq!(server.get("/%E2%82%AC%20res").await, "x");
+        assert_eq!(server.put("/nope/res", &[], "x").await, 409);
+
+        assert_eq!(server.status("DELETE", "/res", &[]).await, 204);
+        assert_eq!(server.status("GET", "/res", &[]).await, 404);
+        assert_eq!(server.status("DELETE", "/res", &[]).await, 404);
+
+        assert_eq!(server.status("MKCOL", "/coll", &[]).await, 201);
+        assert_eq!(server.status("MKCOL", "/coll", &[]).await, 405);
+        assert_eq!(server.status("MKCOL", "/%E2%82%AC%20res", &[]).await, 405);
+        assert_eq!(server.status("MKCOL", "/", &[]).await, 405);
+        assert_eq!(server.status("MKCOL", "/coll/a/b", &[]).await, 409);
+        let with_body = server
+            .request(
+                "MKCOL",
+                "/coll/x",
+                &[("Content-Type", "xzy-foo/bar")],
+                "foo",
+            )
+            .await;
+        assert_eq!(with_body.status, 415);
+        assert_eq!(
+            server
+                .status("DELETE", "/coll", &[("Depth", "infinity")])
+                .await,
+            204
+        );
+
+        assert_eq!(server.status("HEAD", "/%E2%82%AC%20res", &[]).await, 200);
+        assert_eq!(server.status("FOO", "/%E2%82%AC%20res", &[]).await, 405);
+    }
+
+    #[actix_web::test]
+    async fn copymove() {
+        let server = Server::new(&[]);
+        server.put("/res", &[], "hello").await;
+        assert_eq!(
+            server
+                .status("COPY", "/res", &[("Destination", "/res2")])
+                .await,
+            201
+        );
+        let no_overwrite = [("Destination", "/res2"), ("Overwrite", "F")];
+        assert_eq!(server.status("COPY", "/res", &no_overwrite).await, 412);
+        let overwrite = [("Destination", "/res2"), ("Overwrite", "T")];
+        assert_eq!(server.status("COPY", "/res", &overwrite).await, 204);
+        // Overwriting is the default
+        assert_eq!(
+            server
+                .status("COPY", "/res", &[("Destination", "/res2")])
+                .await,
+            204
+        );
+        assert_eq!(
+            server
+                .status(
+                    "COPY",
+                    "/res",
+                    &[("Destination", "http://localhost/nope/res")]
+                )
+                .await,
+            409
+        );
+
+        server.status("MKCOL", "/coll", &[]).await;
+        server.put("/coll/f", &[], "f").await;
+        assert_eq!(
+            server
+                .status("COPY", "/coll/", &[("Destination", "/coll2/")])
+                .await,
+            201
+        );
+        assert_eq!(server.get("/coll2/f").await, "f");
+        let shallow = [("Destination", "/coll3"), ("Depth", "0")];
+        assert_eq!(server.status("COPY", "/coll", &shallow).await, 201);
+        assert_eq!(server.status("GET", "/coll3/f", &[]).await, 404);
+
+        assert_eq!(
+            server
+                .status("MOVE", "/res2", &[("Destination", "/res3")])
+                .await,
+            201
+        );
+        assert_eq!(server.status("GET", "/res2", &[]).await, 404);
+        server.put("/res4", &[], "replaced").await;
+        let no_overwrite = [("Destination", "/res4"), ("Overwrite", "F")];
+        assert_eq!(server.status("MOVE", "/res3", &no_overwrite).await, 412);
+        assert_eq!(server.get("/res4").await, "replaced");
+        assert_eq!(
+            server
+                .status("MOVE", "/res3", &[("Destination", "/res4")])
+                .await,
+            204
+        );
+        assert_eq!(server.get("/res4").await, "hello");
+
+        // A collection replacing another leaves none of the old one's members
+        let overwrite = [("Destination", "/coll3"), ("Overwrite", "T")];
+        assert_eq!(server.status("MOVE", "/coll2", &overwrite).await, 204);
+        assert_eq!(server.get("/coll3/f").await, "f");
+        assert_eq!(server.status("GET", "/coll2/f", &[]).await, 404);
+        // Nothing is left of what was replaced
+        let names: Vec<_> = std::fs::read_dir(&server.root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".replaced"))
+            .collect();
+        assert!(names.is_empty(), "{:?}", names);
+    }
+
+    #[actix_web::test]
+    async fn props() {
+        let server = Server::new(&[]);
+        server.status("MKCOL", "/coll", &[]).await;
+        server.put("/coll/res", &[], "hello2").await;
+        server.put("/coll/%E2%82%AC%20res", &[], "x").await;
+
+        let listing = server
+            .request("PROPFIND", "/coll/", &[("Depth", "1")], "")
+            .await;
+        assert_eq!(listing.status, 207);
+        let mut hrefs: Vec<_> = listing
+            .body
+            .split("<D:href>")
+            .skip(1)
+            .map(|rest| rest.split("</D:href>").next().unwrap())
+            .collect();
+        hrefs.sort();
+        assert_eq!(hrefs, ["/coll/", "/coll/%E2%82%AC%20res", "/coll/res"]);
+        assert!(listing
+            .body
+            .contains("<D:getcontentlength>6</D:getcontentlength>"));
+        assert_eq!(
+            server
+                .status("PROPFIND", "/coll/", &[("Depth", "infinity")])
+                .await,
+            403
+        );
+        let invalid = server
+            .request("PROPFIND", "/coll/", &[("Depth", "0")], "<foo")
+            .await;
+        assert_eq!(invalid.status, 400);
+        let missing = server
+            .request(
+                "PROPFIND",
+                "/coll/res",
+                &[("Depth", "0")],
+                r#"<propfind xmlns="DAV:"><prop><getetag/><foo xmlns="http://x/"/></prop></propfind>"#,
+            )
+            .await;
+        assert!(missing.body.contains("404 Not Found") && missing.body.contains("getetag"));
+
+        let set = server
+            .request("PROPPATCH", "/coll/res", &[], PROPPATCH_SET)
+            .await;
+        assert_eq!(set.status, 207);
+        assert!(set.body.contains("200 OK"));
+        let find = |path| {
+            server.request(
+                "PROPFIND",
+                path,
+                &[("Depth", "0")],
+                r#"<propfind xmlns="DAV:"><prop><color xmlns="http://example.com/ns"/><nons xmlns=""/></prop></propfind>"#,
+            )
+        };
+        let found = find("/coll/res").await;
+        assert!(found.body.contains("red") && found.body.contains(">v</nons>"));
+        assert!(!found.body.contains("404"));
+
+        // Live properties can't be set, and the rest of the update fails with them
+        let live = server
+            .request(
+                "PROPPATCH",
+                "/coll/res",
+                &[],
+                r#"<D:propertyupdate xmlns:D="DAV:"><D:set><D:prop><D:getetag>x</D:getetag><D:foo>1</D:foo></D:prop></D:set></D:propertyupdate>"#,
+            )
+            .await;
+        assert!(live.body.contains("403") && live.body.contains("424"));
+
+        // Dead properties go along with copies and moves
+        server
+            .status("COPY", "/coll/res", &[("Destination", "/coll/copy")])
+            .await;
+        assert!(find("/coll/copy").await.body.contains("red"));
+        server
+            .status("MOVE", "/coll/copy", &[("Destination", "/coll/moved")])
+            .await;
+        assert!(find("/coll/moved").await.body.contains("red"));
+    }
+
+    #[actix_web::test]
+    async fn locks() {
+        let server = Server::new(&["--deny-extension", "exe"]);
+        server.status("MKCOL", "/coll", &[]).await;
+        server.put("/res", &[], "hello").await;
+        server.put("/other", &[], "other").await;
+
+        let lock = server
+            .request("LOCK", "/res", &[("Timeout", "Second-100")], LOCK_INFO)
+            .await;
+        assert_eq!(lock.status, 200);
+        assert!(lock.body.contains("litmus"));
+        let token = lock.headers.get("Lock-Token").unwrap().to_str().unwrap();
+        let tagged = format!("<http://localhost/res> ({})", token);
+        let with_not = format!("({}) (Not <DAV:no-lock>)", token);
+        let token_list = format!("({})", token);
+
+        assert_eq!(server.put("/res", &[], "z").await, 423);
+        assert_eq!(server.put("/res", &[("If", &token_list)], "z").await, 204);
+        assert_eq!(server.put("/res", &[("If", &tagged)], "z").await, 204);
+        let bogus = "(<opaquelocktoken:foo>)";
+        assert_eq!(server.put("/res", &[("If", bogus)], "z").await, 412);
+        let bogus_or_not = "(<opaquelocktoken:foo>) (Not <DAV:no-lock>)";
+        assert_eq!(server.put("/res", &[("If", bogus_or_not)], "z").await, 423);
+        assert_eq!(server.put("/res", &[("If", &with_not)], "z").await, 204);
+
+        let conflict = server.request("LOCK", "/res", &[], LOCK_INFO).await;
+        assert_eq!(conflict.status, 423);
+        assert_eq!(server.status("DELETE", "/res", &[]).await, 423);
+        let proppatch = server
+            .request("PROPPATCH", "/res", &[], PROPPATCH_SET)
+            .await;
+        assert_eq!(proppatch.status, 423);
+
+        let refresh = server
+            .request(
+                "LOCK",
+                "/res",
+                &[("If", &token_list), ("Timeout", "Second-500")],
+                "",
+            )
+            .await;
+        assert_eq!(refresh.status, 200);
+        assert!(refresh.body.contains("Second-500"));
+        let discovery = server
+            .request("PROPFIND", "/res", &[("Depth", "0")], "")
+            .await;
+        assert!(discovery.body.contains(token.trim_matches(['<', '>'])));
+
+        let bad_token = [("Lock-Token", "<opaquelocktoken:nope>")];
+        assert_eq!(server.status("UNLOCK", "/res", &bad_token).await, 409);
+        assert_eq!(
+            server
+                .status("UNLOCK", "/res", &[("Lock-Token", token)])
+                .await,
+            204
+        );
+        assert_eq!(server.put("/res", &[], "z").await, 204);
+
+        // Locks on collections cover their members
+        let lock = server.request("LOCK", "/coll", &[], LOCK_INFO).await;
+        let token = lock.headers.get("Lock-Token").unwrap().to_str().unwrap();
+        let token_list = format!("({})", token);
+        assert_eq!(server.put("/coll/new", &[], "n").await, 423);
+        assert_eq!(
+            server.put("/coll/new", &[("If", &token_list)], "n").await,
+            201
+        );
+        let into_locked = [("Destination", "/coll/other")];
+        assert_eq!(server.status("MOVE", "/other", &into_locked).await, 423);
+        server
+            .status("UNLOCK", "/coll", &[("Lock-Token", token)])
+            .await;
+
+        let shared = LOCK_INFO.replace("exclusive", "shared");
+        let first = server.request("LOCK", "/res", &[], &shared).await;
+        let second = server.request("LOCK", "/res", &[], &shared).await;
+        assert_eq!((first.status.as_u16(), second.status.as_u16()), (200, 200));
+        let exclusive = server.request("LOCK", "/res", &[], LOCK_INFO).await;
+        assert_eq!(exclusive.status, 423);
+
+        // Locking an unmapped path creates an empty file, like an upload would
+        let unmapped = server.request("LOCK", "/lockme", &[], LOCK_INFO).await;
+        assert_eq!(unmapped.status, 201);
+        assert_eq!(server.status("GET", "/lockme", &[]).await, 200);
+        let denied = server.request("LOCK", "/lockme.exe", &[], LOCK_INFO).await;
+        assert_eq!(denied.status, 415);
+        assert!(!server.root.join("lockme.exe").exists());
+    }
+
+    #[actix_web::test]
+    async fn locks_hold_for_forms_and_uploads() {
+        let server = Server::new(&[]);
+        server.status("MKCOL", "/coll", &[]).await;
+        server.put("/coll/res", &[], "hello").await;
+        server.put("/other", &[], "other").await;
+        let lock = server.request("LOCK", "/coll", &[], LOCK_INFO).await;
+        assert_eq!(lock.status, 200);
+
+        let form = [("Content-Type", "application/x-www-form-urlencoded")];
+        let forms = [
+            ("/coll/", "action=delete&path=res"),
+            ("/coll/", "action=rename&path=res&to=renamed"),
+            ("/coll/", "action=mkdir&folder=new"),
+            ("/", "action=move&path=other&destination=/coll"),
+        ];
+        for (path, body) in forms {
+            let response = server.request("POST", path, &form, body).await;
+            assert_eq!(response.status, 423, "{}", body);
+        }
+
+        let boundary = "XyZ";
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        let multipart = format!(
+            "--{0}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"up.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\nup\r\n--{0}--\r\n",
+            boundary
+        );
+        let upload = [("Content-Type", content_type.as_str())];
+        let response = server.request("POST", "/coll/", &upload, &multipart).await;
+        assert_eq!(response.status, 423);
+
+        assert_eq!(server.get("/coll/res").await, "hello");
+        assert!(server.root.join("other").exists());
+        for name in ["renamed", "new", "up.txt"] {
+            assert!(!server.root.join("coll").join(name).exists(), "{}", name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn locks_belong_to_their_user() {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let htpasswd = std::env::temp_dir().join(format!("htpasswd-{}", uuid::Uuid::new_v4()));
+        let hashes = ["alice", "bob"].map(|user| {
+            let hash = bcrypt::hash(format!("{}-password", user), 4).unwrap();
+            format!("{}:{}\n", user, hash)
+        });
+        std::fs::write(&htpasswd, hashes.concat()).unwrap();
+        let server = Server::new(&["--auth-file", &htpasswd.to_string_lossy()]);
+        let _ = std::fs::remove_file(&htpasswd);
+        let [alice, bob] = ["alice", "bob"].map(|user| {
+            format!(
+                "Basic {}",
+                STANDARD.encode(format!("{0}:{0}-password", user))
+            )
+        });
+        let as_alice = [("Authorization", alice.as_str())];
+        let as_bob = [("Authorization", bob.as_str())];
+
+        server.put("/res", &as_alice, "hello").await;
+        let lock = server.request("LOCK", "/res", &as_alice, LOCK_INFO).await;
+        assert_eq!(lock.status, 200);
+        let token = lock.headers.get("Lock-Token").unwrap().to_str().unwrap();
+        let bare_token = token.trim_matches(['<', '>']);
+        let token_list = format!("({})", token);
+
+        // Only the user holding the lock gets to see its token
+        let as_alice_depth = [as_alice[0], ("Depth", "0")];
+        let as_bob_depth = [as_bob[0], ("Depth", "0")];
+        let seen_by_alice = server
+            .request("PROPFIND", "/res", &as_alice_depth, "")
+            .await;
+        assert!(seen_by_alice.body.contains(bare_token));
+        let seen_by_bob = server.request("PROPFIND", "/res", &as_bob_depth, "").await;
+        assert!(seen_by_bob.body.contains("activelock"));
+        assert!(!seen_by_bob.body.contains(bare_token));
+
+        // And to use it
+        let bob_with_token = [as_bob[0], ("If", token_list.as_str())];
+        assert_eq!(server.put("/res", &bob_with_token, "z").await, 412);
+        let bob_unlock = [as_bob[0], ("Lock-Token", token)];
+        assert_eq!(server.status("UNLOCK", "/res", &bob_unlock).await, 409);
+        let alice_with_token = [as_alice[0], ("If", token_list.as_str())];
+        assert_eq!(server.put("/res", &alice_with_token, "z").await, 204);
+        let alice_unlock = [as_alice[0], ("Lock-Token", token)];
+        assert_eq!(server.status("UNLOCK", "/res", &alice_unlock).await, 204);
+    }
+
+    #[actix_web::test]
+    async fn etags() {
+        let server = Server::new(&[]);
+        server.put("/res", &[], "hello").await;
+        let get = server.request("GET", "/res", &[], "").await;
+        let etag = get.headers.get("ETag").unwrap().to_str().unwrap();
+        let condition = format!("([{}])", etag);
+        assert_eq!(server.put("/res", &[("If", &condition)], "q").await, 204);
+        assert_eq!(server.put("/res", &[("If", &condition)], "q").await, 412);
+    }
+}
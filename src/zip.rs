@@ -0,0 +1,576 @@
+//! Minimal ZIP writer that never seeks, so that archives can be streamed to the client while
+//! they are being created.
+//!
+//! Sizes and checksums are only known after an entry's data is written, so they go into a data
+//! descriptor after the data instead of the local header. ZIP64 records are used for entries that
+//! could reach 4 GiB and whenever the central directory outgrows the classic format.
+
+use flate2::{write::DeflateEncoder, Compression};
+use std::{
+    io::{self, Read, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+const EXTENDED_TIMESTAMP_EXTRA_FIELD_ID: u16 = 0x5455;
+
+/// Sizes are in the data descriptor, file name is UTF-8
+const GENERAL_PURPOSE_FLAGS: u16 = (1 << 3) | (1 << 11);
+
+const METHOD_STORE: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+const VERSION_NEEDED: u16 = 20;
+const VERSION_NEEDED_ZIP64: u16 = 45;
+/// Unix attributes, specification version 4.5
+const VERSION_MADE_BY: u16 = (3 << 8) | 45;
+
+/// Files at least this large get ZIP64 sizes, leaving headroom for deflate's overhead on
+/// incompressible data
+const ZIP64_FILE_SIZE_THRESHOLD: u64 = 0xF000_0000;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const MSDOS_DIRECTORY_ATTRIBUTE: u32 = 0x10;
+
+struct CentralDirectoryEntry {
+    name: String,
+    method: u16,
+    zip64: bool,
+    dos_time: u16,
+    dos_date: u16,
+    unix_time: u32,
+    crc32: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    local_header_offset: u64,
+    external_attributes: u32,
+}
+
+/// Counts the bytes written so that offsets are known without seeking
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct ZipWriter<W: Write> {
+    writer: CountingWriter<W>,
+    entries: Vec<CentralDirectoryEntry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ZipWriter {
+            writer: CountingWriter { inner, count: 0 },
+            entries: Vec::new(),
+        }
+    }
+
+    /// Adds an empty directory entry. `name` should end with a slash.
+    pub fn add_directory(
+        &mut self,
+        name: String,
+        modified: SystemTime,
+        mode: u32,
+    ) -> io::Result<()> {
+        let external_attributes = ((S_IFDIR | mode) << 16) | MSDOS_DIRECTORY_ATTRIBUTE;
+        let mut entry =
+            self.start_entry(name, modified, METHOD_STORE, false, external_attributes)?;
+        self.finish_entry(&mut entry, 0, crc32fast::Hasher::new().finalize(), 0)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Adds a file entry with the contents of `reader`, whose length should be about `size`.
+    pub fn add_file<R: Read>(
+        &mut self,
+        name: String,
+        modified: SystemTime,
+        mode: u32,
+        size: u64,
+        compress: bool,
+        reader: &mut R,
+    ) -> io::Result<()> {
+        let method = if compress {
+            METHOD_DEFLATE
+        } else {
+            METHOD_STORE
+        };
+        let zip64 = size >= ZIP64_FILE_SIZE_THRESHOLD;
+        let mut entry = self.start_entry(name, modified, method, zip64, (S_IFREG | mode) << 16)?;
+
+        let data_start = self.writer.count;
+        let mut hasher = crc32fast::Hasher::new();
+        let uncompressed_size = if compress {
+            let mut encoder = DeflateEncoder::new(&mut self.writer, Compression::default());
+            let uncompressed_size = copy_with_crc32(reader, &mut encoder, &mut hasher)?;
+            encoder.finish()?;
+            uncompressed_size
+        } else {
+            copy_with_crc32(reader, &mut self.writer, &mut hasher)?
+        };
+        let compressed_size = self.writer.count - data_start;
+
+        self.finish_entry(
+            &mut entry,
+            compressed_size,
+            hasher.finalize(),
+            uncompressed_size,
+        )?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let central_directory_offset = self.writer.count;
+        for entry in &self.entries {
+            let mut zip64_extra = Vec::new();
+            let uncompressed_size = if entry.zip64 || entry.uncompressed_size >= u32::MAX as u64 {
+                zip64_extra.extend_from_slice(&entry.uncompressed_size.to_le_bytes());
+                u32::MAX
+            } else {
+                entry.uncompressed_size as u32
+            };
+            let compressed_size = if entry.zip64 || entry.compressed_size >= u32::MAX as u64 {
+                zip64_extra.extend_from_slice(&entry.compressed_size.to_le_bytes());
+                u32::MAX
+            } else {
+                entry.compressed_size as u32
+            };
+            let local_header_offset = if entry.local_header_offset >= u32::MAX as u64 {
+                zip64_extra.extend_from_slice(&entry.local_header_offset.to_le_bytes());
+                u32::MAX
+            } else {
+                entry.local_header_offset as u32
+            };
+
+            let mut extra = extended_timestamp_extra_field(entry.unix_time);
+            if !zip64_extra.is_empty() {
+                extra.extend_from_slice(&ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+                extra.extend_from_slice(&(zip64_extra.len() as u16).to_le_bytes());
+                extra.extend_from_slice(&zip64_extra);
+            }
+            let version_needed = if zip64_extra.is_empty() {
+                VERSION_NEEDED
+            } else {
+                VERSION_NEEDED_ZIP64
+            };
+
+            let mut header = Vec::with_capacity(46 + entry.name.len() + extra.len());
+            header.extend_from_slice(&CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes());
+            header.extend_from_slice(&VERSION_MADE_BY.to_le_bytes());
+            header.extend_from_slice(&version_needed.to_le_bytes());
+            header.extend_from_slice(&GENERAL_PURPOSE_FLAGS.to_le_bytes());
+            header.extend_from_slice(&entry.method.to_le_bytes());
+            header.extend_from_slice(&entry.dos_time.to_le_bytes());
+            header.extend_from_slice(&entry.dos_date.to_le_bytes());
+            header.extend_from_slice(&entry.crc32.to_le_bytes());
+            header.extend_from_slice(&compressed_size.to_le_bytes());
+            header.extend_from_slice(&uncompressed_size.to_le_bytes());
+            header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            // File comment length, disk number start, internal file attributes
+            header.extend_from_slice(&[0; 6]);
+            header.extend_from_slice(&entry.external_attributes.to_le_bytes());
+            header.extend_from_slice(&local_header_offset.to_le_bytes());
+            header.extend_from_slice(entry.name.as_bytes());
+            header.extend_from_slice(&extra);
+            self.writer.write_all(&header)?;
+        }
+        let central_directory_size = self.writer.count - central_directory_offset;
+        let num_entries = self.entries.len() as u64;
+
+        let needs_zip64 = num_entries >= u16::MAX as u64
+            || central_directory_size >= u32::MAX as u64
+            || central_directory_offset >= u32::MAX as u64;
+        if needs_zip64 {
+            let zip64_end_offset = self.writer.count;
+
+            let mut record = Vec::with_capacity(56 + 20);
+            record.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+            // Size of the remaining record
+            record.extend_from_slice(&44u64.to_le_bytes());
+            record.extend_from_slice(&VERSION_MADE_BY.to_le_bytes());
+            record.extend_from_slice(&VERSION_NEEDED_ZIP64.to_le_bytes());
+            // Number of this disk, disk with the central directory
+            record.extend_from_slice(&[0; 8]);
+            record.extend_from_slice(&num_entries.to_le_bytes());
+            record.extend_from_slice(&num_entries.to_le_bytes());
+            record.extend_from_slice(&central_directory_size.to_le_bytes());
+            record.extend_from_slice(&central_directory_offset.to_le_bytes());
+
+            record
+                .extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE.to_le_bytes());
+            // Disk with the ZIP64 end of central directory record
+            record.extend_from_slice(&[0; 4]);
+            record.extend_from_slice(&zip64_end_offset.to_le_bytes());
+            // Total number of disks
+            record.extend_from_slice(&1u32.to_le_bytes());
+            self.writer.write_all(&record)?;
+        }
+
+        let mut record = Vec::with_capacity(22);
+        record.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        // Number of this disk, disk with the central directory
+        record.extend_from_slice(&[0; 4]);
+        let num_entries = num_entries.min(u16::MAX as u64) as u16;
+        record.extend_from_slice(&num_entries.to_le_bytes());
+        record.extend_from_slice(&num_entries.to_le_bytes());
+        record
+            .extend_from_slice(&(central_directory_size.min(u32::MAX as u64) as u32).to_le_bytes());
+        record.extend_from_slice(
+            &(central_directory_offset.min(u32::MAX as u64) as u32).to_le_bytes(),
+        );
+        // Comment length
+        record.extend_from_slice(&[0; 2]);
+        self.writer.write_all(&record)?;
+
+        self.writer.flush()?;
+        Ok(self.writer.inner)
+    }
+
+    fn start_entry(
+        &mut self,
+        name: String,
+        modified: SystemTime,
+        method: u16,
+        zip64: bool,
+        external_attributes: u32,
+    ) -> io::Result<CentralDirectoryEntry> {
+        let unix_time = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs().min(u32::MAX as u64) as u32);
+        let (dos_time, dos_date) = dos_date_time(unix_time);
+
+        let mut extra = extended_timestamp_extra_field(unix_time);
+        let (version_needed, placeholder_size) = if zip64 {
+            // Real sizes follow in the data descriptor, but the field has to be present for it
+            // to have 8 byte sizes
+            extra.extend_from_slice(&ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+            extra.extend_from_slice(&16u16.to_le_bytes());
+            extra.extend_from_slice(&[0; 16]);
+            (VERSION_NEEDED_ZIP64, u32::MAX)
+        } else {
+            (VERSION_NEEDED, 0)
+        };
+
+        let mut header = Vec::with_capacity(30 + name.len() + extra.len());
+        header.extend_from_slice(&LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes());
+        header.extend_from_slice(&version_needed.to_le_bytes());
+        header.extend_from_slice(&GENERAL_PURPOSE_FLAGS.to_le_bytes());
+        header.extend_from_slice(&method.to_le_bytes());
+        header.extend_from_slice(&dos_time.to_le_bytes());
+        header.extend_from_slice(&dos_date.to_le_bytes());
+        // CRC-32 is in the data descriptor
+        header.extend_from_slice(&[0; 4]);
+        header.extend_from_slice(&placeholder_size.to_le_bytes());
+        header.extend_from_slice(&placeholder_size.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&extra);
+
+        let local_header_offset = self.writer.count;
+        self.writer.write_all(&header)?;
+
+        Ok(CentralDirectoryEntry {
+            name,
+            method,
+            zip64,
+            dos_time,
+            dos_date,
+            unix_time,
+            crc32: 0,
+            compressed_size: 0,
+            uncompressed_size: 0,
+            local_header_offset,
+            external_attributes,
+        })
+    }
+
+    fn finish_entry(
+        &mut self,
+        entry: &mut CentralDirectoryEntry,
+        compressed_size: u64,
+        crc32: u32,
+        uncompressed_size: u64,
+    ) -> io::Result<()> {
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend_from_slice(&DATA_DESCRIPTOR_SIGNATURE.to_le_bytes());
+        descriptor.extend_from_slice(&crc32.to_le_bytes());
+        if entry.zip64 {
+            descriptor.extend_from_slice(&compressed_size.to_le_bytes());
+            descriptor.extend_from_slice(&uncompressed_size.to_le_bytes());
+        } else if compressed_size >= u32::MAX as u64 || uncompressed_size >= u32::MAX as u64 {
+            return Err(io::Error::other(
+                "File grew past 4 GiB while being archived",
+            ));
+        } else {
+            descriptor.extend_from_slice(&(compressed_size as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(uncompressed_size as u32).to_le_bytes());
+        }
+        self.writer.write_all(&descriptor)?;
+
+        entry.crc32 = crc32;
+        entry.compressed_size = compressed_size;
+        entry.uncompressed_size = uncompressed_size;
+        Ok(())
+    }
+}
+
+fn copy_with_crc32<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    hasher: &mut crc32fast::Hasher,
+) -> io::Result<u64> {
+    let mut buf = vec![0; 64 << 10];
+    let mut total = 0;
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..len]);
+        writer.write_all(&buf[..len])?;
+        total += len as u64;
+    }
+}
+
+/// Extra field holding the modification time in UTC, since DOS timestamps have no time zone
+fn extended_timestamp_extra_field(unix_time: u32) -> Vec<u8> {
+    let mut extra = Vec::with_capacity(9);
+    extra.extend_from_slice(&EXTENDED_TIMESTAMP_EXTRA_FIELD_ID.to_le_bytes());
+    extra.extend_from_slice(&5u16.to_le_bytes());
+    // Only the modification time is present
+    extra.push(1);
+    extra.extend_from_slice(&unix_time.to_le_bytes());
+    extra
+}
+
+/// Converts seconds since the Unix epoch to MS-DOS time and date, in UTC
+fn dos_date_time(unix_time: u32) -> (u16, u16) {
+    let days = unix_time / 86400;
+    let seconds_of_day = unix_time % 86400;
+
+    // Civil date from days since 1970-01-01, by Howard Hinnant
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u32::from(month <= 2);
+
+    // DOS dates start at 1980
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let year = (year - 1980).min(127);
+
+    let dos_time = ((seconds_of_day / 3600) << 11)
+        | (((seconds_of_day % 3600) / 60) << 5)
+        | ((seconds_of_day % 60) / 2);
+    let dos_date = (year << 9) | (month << 5) | day;
+    (dos_time as u16, dos_date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::File,
+        io::{Cursor, Seek},
+        time::Duration,
+    };
+
+    /// 2023-11-14 22:13:20 UTC
+    const MODIFIED: u64 = 1_700_000_000;
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(MODIFIED)
+    }
+
+    fn read_entry<R: Read + Seek>(archive: &mut ::zip::ZipArchive<R>, name: &str) -> Vec<u8> {
+        let mut contents = Vec::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn entries_read_back() {
+        let text = "Grüße, 世界\n".repeat(1000);
+        let mut writer = ZipWriter::new(Vec::new());
+        writer
+            .add_directory("dir/".to_owned(), modified(), 0o755)
+            .unwrap();
+        writer
+            .add_file(
+                "dir/stored.txt".to_owned(),
+                modified(),
+                0o644,
+                5,
+                false,
+                &mut "hello".as_bytes(),
+            )
+            .unwrap();
+        writer
+            .add_file(
+                "dir/Übersicht 日本.txt".to_owned(),
+                modified(),
+                0o600,
+                text.len() as u64,
+                true,
+                &mut text.as_bytes(),
+            )
+            .unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 3);
+        let names: Vec<_> = archive.file_names().collect();
+        assert!(names.contains(&"dir/Übersicht 日本.txt"));
+
+        let dir = archive.by_name("dir/").unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(dir.unix_mode(), Some(S_IFDIR | 0o755));
+        drop(dir);
+
+        let stored = archive.by_name("dir/stored.txt").unwrap();
+        assert_eq!(stored.compression(), ::zip::CompressionMethod::Stored);
+        assert_eq!(stored.unix_mode(), Some(S_IFREG | 0o644));
+        let time = stored.last_modified();
+        assert_eq!((time.year(), time.month(), time.day()), (2023, 11, 14),);
+        assert_eq!((time.hour(), time.minute(), time.second()), (22, 13, 20));
+        drop(stored);
+        assert_eq!(read_entry(&mut archive, "dir/stored.txt"), b"hello");
+
+        let compressed = archive.by_name("dir/Übersicht 日本.txt").unwrap();
+        assert_eq!(compressed.compression(), ::zip::CompressionMethod::Deflated);
+        assert!(compressed.compressed_size() < text.len() as u64);
+        drop(compressed);
+        assert_eq!(
+            read_entry(&mut archive, "dir/Übersicht 日本.txt"),
+            text.as_bytes()
+        );
+    }
+
+    #[test]
+    fn zip64_entries_read_back() {
+        // Claiming a size past the threshold makes the entry ZIP64 whatever its real size
+        let mut writer = ZipWriter::new(Vec::new());
+        for compress in [false, true] {
+            writer
+                .add_file(
+                    format!("zip64-{}.bin", compress),
+                    modified(),
+                    0o644,
+                    ZIP64_FILE_SIZE_THRESHOLD,
+                    compress,
+                    &mut [7u8; 1000].as_slice(),
+                )
+                .unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(read_entry(&mut archive, "zip64-false.bin"), [7; 1000]);
+        assert_eq!(read_entry(&mut archive, "zip64-true.bin"), [7; 1000]);
+    }
+
+    #[test]
+    fn zip64_end_of_central_directory_reads_back() {
+        // More entries than the classic end of central directory record can count
+        let count = u16::MAX as usize + 10;
+        let mut writer = ZipWriter::new(Vec::new());
+        for i in 0..count {
+            writer
+                .add_directory(format!("{}/", i), modified(), 0o755)
+                .unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), count);
+        assert!(archive
+            .by_name(&format!("{}/", count - 1))
+            .unwrap()
+            .is_dir());
+    }
+
+    #[test]
+    #[ignore = "writes a 4.5 GiB file"]
+    fn entry_over_4_gib_reads_back() {
+        let size = (4 << 30) + (512 << 20);
+        let path = std::env::temp_dir().join(format!("zip-test-{}.zip", std::process::id()));
+        let mut writer = ZipWriter::new(File::create(&path).unwrap());
+        writer
+            .add_file(
+                "big.bin".to_owned(),
+                modified(),
+                0o644,
+                size,
+                false,
+                &mut io::repeat(1).take(size),
+            )
+            .unwrap();
+        // Its local header is past 4 GiB, so it needs a ZIP64 offset
+        writer
+            .add_file(
+                "after.txt".to_owned(),
+                modified(),
+                0o644,
+                5,
+                false,
+                &mut "after".as_bytes(),
+            )
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut archive = ::zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut big = archive.by_name("big.bin").unwrap();
+        assert_eq!(big.size(), size);
+        // Reading it to the end checks the CRC-32
+        assert_eq!(io::copy(&mut big, &mut io::sink()).unwrap(), size);
+        drop(big);
+        assert_eq!(read_entry(&mut archive, "after.txt"), b"after");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dos_date_time_is_utc() {
+        let (time, date) = dos_date_time(MODIFIED as u32);
+        assert_eq!(time, (22 << 11) | (13 << 5) | (20 / 2));
+        assert_eq!(date, ((2023 - 1980) << 9) | (11 << 5) | 14);
+        // Before 1980, DOS dates can't go
+        assert_eq!(dos_date_time(0), (0, (1 << 5) | 1));
+    }
+}
@@ -0,0 +1,327 @@
+// Uploads files with the tus protocol, so that an upload cut off by a dropped connection or a
+// closed tab continues where it stopped instead of starting over. Files can be picked, whole
+// folders picked or dropped anywhere on the page, and each gets a row with its progress.
+"use strict";
+
+const TUS_VERSION = "1.0.0";
+const CHUNK_SIZE = 32 << 20;
+const RETRY_DELAYS = [1000, 3000, 5000, 10000, 20000];
+// Only there when logged in with the login form, whose session needs it for every change
+const CSRF_TOKEN = document.querySelector('meta[name="csrf-token"]')?.content;
+
+class UploadError extends Error {}
+
+const encodeMetadata = (metadata) =>
+  Object.entries(metadata)
+    .map(([key, value]) => {
+      const bytes = new TextEncoder().encode(value);
+      return `${key} ${btoa(Array.from(bytes, (byte) => String.fromCharCode(byte)).join(""))}`;
+    })
+    .join(",");
+
+const ARCHIVE_NAME = /\.(zip|tar\.gz|tgz)$/i;
+
+const refusal = (status) =>
+  ({ 413: "too large", 415: "type not allowed" })[status] ?? `refused (${status})`;
+
+const uploadOffset = (xhr) => Number(xhr.getResponseHeader("Upload-Offset"));
+
+// Lets the server throw away what it has of an upload. The server refuses while it is still busy
+// with a request that was just aborted, so that is retried a few times.
+const terminate = (url, retries) => {
+  const xhr = new XMLHttpRequest();
+  xhr.open("DELETE", url);
+  xhr.setRequestHeader("Tus-Resumable", TUS_VERSION);
+  if (CSRF_TOKEN) xhr.setRequestHeader("X-CSRF-Token", CSRF_TOKEN);
+  xhr.onload = () => {
+    if (xhr.status === 409 && retries < RETRY_DELAYS.length) {
+      setTimeout(() => terminate(url, retries + 1), RETRY_DELAYS[retries]);
+    }
+  };
+  xhr.send();
+};
+
+class Transfer {
+  constructor(file, relativePath) {
+    this.file = file;
+    this.relativePath = relativePath;
+    this.directory = decodeURIComponent(location.pathname);
+    // Archives are unpacked by the server when "Extract" was ticked as they were added
+    this.extract =
+      Boolean(document.getElementById("extract")?.checked) && ARCHIVE_NAME.test(file.name);
+    // Uploads are remembered by file and directory so that adding the same file again resumes it
+    this.key = [
+      "tus",
+      this.directory,
+      relativePath,
+      file.size,
+      file.lastModified,
+      this.extract,
+    ].join(":");
+    this.url = null;
+    this.xhr = null;
+    this.state = "queued";
+
+    this.row = document.createElement("div");
+    this.row.className = "UploadRow";
+    const name = document.createElement("span");
+    name.className = "UploadName";
+    name.textContent = relativePath;
+    this.progress = document.createElement("progress");
+    this.progress.className = "UploadProgress";
+    this.progress.max = Math.max(file.size, 1);
+    this.progress.value = 0;
+    this.status = document.createElement("span");
+    this.status.className = "UploadState";
+    this.cancelButton = this.button("Cancel", () => this.cancel());
+    this.retryButton = this.button("Retry", () => this.retry());
+    this.row.append(name, this.progress, this.status, this.cancelButton, this.retryButton);
+    this.setState("queued", "Waiting");
+  }
+
+  button(text, onclick) {
+    const button = document.createElement("button");
+    button.className = "UploadButton";
+    button.type = "button";
+    button.textContent = text;
+    button.onclick = onclick;
+    return button;
+  }
+
+  setState(state, text) {
+    this.state = state;
+    this.status.textContent = text;
+    this.cancelButton.hidden = !["queued", "uploading"].includes(state);
+    this.retryButton.hidden = !["failed", "cancelled"].includes(state);
+  }
+
+  setOffset(offset) {
+    this.progress.value = offset;
+    this.status.textContent = `${Math.floor((offset * 100) / this.progress.max)}%`;
+  }
+
+  request(method, url, headers, body) {
+    return new Promise((resolve, reject) => {
+      const xhr = new XMLHttpRequest();
+      this.xhr = xhr;
+      xhr.open(method, url);
+      xhr.setRequestHeader("Tus-Resumable", TUS_VERSION);
+      if (CSRF_TOKEN) xhr.setRequestHeader("X-CSRF-Token", CSRF_TOKEN);
+      for (const [name, value] of Object.entries(headers)) {
+        xhr.setRequestHeader(name, value);
+      }
+      if (method === "PATCH") {
+        const offset = Number(headers["Upload-Offset"]);
+        xhr.upload.onprogress = (event) => this.setOffset(offset + event.loaded);
+      }
+      xhr.onload = () => resolve(xhr);
+      xhr.onerror = () => reject(new Error("connection lost"));
+      xhr.onabort = () => reject(new UploadError("cancelled"));
+      xhr.send(body);
+    });
+  }
+
+  // Continues the upload started earlier for the same file, if the server still has it
+  async resume() {
+    const url = localStorage.getItem(this.key);
+    if (!url) {
+      return null;
+    }
+    const xhr = await this.request("HEAD", url, {});
+    if (xhr.status !== 200) {
+      localStorage.removeItem(this.key);
+      return null;
+    }
+    this.url = url;
+    return uploadOffset(xhr);
+  }
+
+  async create() {
+    const xhr = await this.request("POST", "/.tus/", {
+      "Upload-Length": this.file.size,
+      "Upload-Metadata": encodeMetadata({
+        filename: this.file.name,
+        relativePath: this.relativePath,
+        directory: this.directory,
+        lastModified: String(this.file.lastModified),
+        ...(this.extract && { extract: "true" }),
+      }),
+    });
+    if (xhr.status !== 201) {
+      throw new UploadError(refusal(xhr.status));
+    }
+    this.url = xhr.getResponseHeader("Location");
+    localStorage.setItem(this.key, this.url);
+    return 0;
+  }
+
+  async upload() {
+    let offset = (await this.resume()) ?? (await this.create());
+    let retries = 0;
+    while (offset < this.file.size) {
+      this.setOffset(offset);
+      try {
+        const xhr = await this.request(
+          "PATCH",
+          this.url,
+          { "Content-Type": "application/offset+octet-stream", "Upload-Offset": offset },
+          this.file.slice(offset, offset + CHUNK_SIZE)
+        );
+        if (xhr.status === 204) {
+          offset = uploadOffset(xhr);
+          retries = 0;
+          continue;
+        }
+        // Anything but a server error or a conflicting offset won't get better by retrying
+        if (xhr.status < 500 && xhr.status !== 409) {
+          localStorage.removeItem(this.key);
+          throw new UploadError(refusal(xhr.status));
+        }
+      } catch (error) {
+        if (error instanceof UploadError) {
+          throw error;
+        }
+      }
+
+      if (retries === RETRY_DELAYS.length) {
+        throw new UploadError("connection lost");
+      }
+      this.status.textContent = "Reconnecting";
+      await new Promise((resolve) => setTimeout(resolve, RETRY_DELAYS[retries++]));
+      if (this.state !== "uploading") {
+        throw new UploadError("cancelled");
+      }
+      // Ask the server how much arrived before continuing
+      const xhr = await this.request("HEAD", this.url, {}).catch(() => null);
+      if (xhr?.status === 200) {
+        offset = uploadOffset(xhr);
+      }
+    }
+    localStorage.removeItem(this.key);
+  }
+
+  async run() {
+    this.setState("uploading", "Starting");
+    try {
+      await this.upload();
+      this.setOffset(this.progress.max);
+      this.setState("done", "Done");
+    } catch (error) {
+      if (this.state === "uploading") {
+        this.setState("failed", `Failed: ${error.message}`);
+      }
+    }
+  }
+
+  cancel() {
+    const wasUploading = this.state === "uploading";
+    this.setState("cancelled", "Cancelled");
+    if (wasUploading) {
+      this.xhr?.abort();
+    }
+    if (this.url) {
+      terminate(this.url, 0);
+      this.url = null;
+    }
+    localStorage.removeItem(this.key);
+  }
+
+  retry() {
+    this.setState("queued", "Waiting");
+    runTransfers();
+  }
+}
+
+const transfers = [];
+let running = false;
+
+// Uploads one file at a time, reloading the listing once everything went through
+const runTransfers = async () => {
+  if (running) {
+    return;
+  }
+  running = true;
+  let transfer;
+  while ((transfer = transfers.find((transfer) => transfer.state === "queued"))) {
+    await transfer.run();
+  }
+  running = false;
+  const settled = transfers.every((transfer) => ["done", "cancelled"].includes(transfer.state));
+  if (settled && transfers.some((transfer) => transfer.state === "done")) {
+    location.reload();
+  }
+};
+
+const queueFiles = (files) => {
+  const list = document.getElementById("uploads");
+  for (const { file, relativePath } of files) {
+    const transfer = new Transfer(file, relativePath);
+    transfers.push(transfer);
+    list.append(transfer.row);
+  }
+  list.hidden = transfers.length === 0;
+  runTransfers();
+};
+
+// Used by both file inputs, where folder uploads give each file its path inside the folder
+const uploadFiles = (input) => {
+  queueFiles(
+    Array.from(input.files, (file) => ({
+      file,
+      relativePath: file.webkitRelativePath || file.name,
+    }))
+  );
+  input.value = "";
+};
+
+const readEntry = async (entry, path, files) => {
+  if (entry.isFile) {
+    const file = await new Promise((resolve, reject) => entry.file(resolve, reject));
+    files.push({ file, relativePath: path + file.name });
+  } else if (entry.isDirectory) {
+    const reader = entry.createReader();
+    // Directories are read in batches until an empty one comes back
+    let batch;
+    do {
+      batch = await new Promise((resolve, reject) => reader.readEntries(resolve, reject));
+      for (const child of batch) {
+        await readEntry(child, `${path}${entry.name}/`, files);
+      }
+    } while (batch.length > 0);
+  }
+};
+
+const isFileDrag = (event) => event.dataTransfer?.types.includes("Files");
+
+document.addEventListener("dragover", (event) => {
+  if (isFileDrag(event)) {
+    event.preventDefault();
+    document.body.classList.add("Dragging");
+  }
+});
+
+document.addEventListener("dragleave", (event) => {
+  // Only when the pointer leaves the window
+  if (!event.relatedTarget) {
+    document.body.classList.remove("Dragging");
+  }
+});
+
+document.addEventListener("drop", async (event) => {
+  if (!isFileDrag(event)) {
+    return;
+  }
+  event.preventDefault();
+  document.body.classList.remove("Dragging");
+  // The entries have to be taken before anything is awaited
+  const entries = Array.from(event.dataTransfer.items, (item) => item.webkitGetAsEntry?.());
+  if (entries.some((entry) => !entry)) {
+    queueFiles(Array.from(event.dataTransfer.files, (file) => ({ file, relativePath: file.name })));
+    return;
+  }
+  const files = [];
+  for (const entry of entries) {
+    await readEntry(entry, "", files);
+  }
+  queueFiles(files);
+});